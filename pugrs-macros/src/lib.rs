@@ -0,0 +1,142 @@
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate pugrs;
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use pugrs::{input, Compiler};
+use quote::quote;
+use std::env;
+use std::path::{Path, PathBuf};
+use syn::{parse_macro_input, DeriveInput, LitStr};
+
+// `pug!("template")` is the HTML of an inline template as a `&'static str`.
+// Includes are read relative to the crate root.
+#[proc_macro]
+pub fn pug(input: TokenStream) -> TokenStream {
+    let template = parse_macro_input!(input as LitStr);
+    let path = crate_dir().join("pug!");
+    match compile(&template.value(), &path, None, template.span()) {
+        Ok((html, deps)) => {
+            // The first one is the template itself
+            let deps = deps[1..].iter().map(|dep| dep.to_string_lossy());
+            quote!({
+                #(const _: &[u8] = include_bytes!(#deps);)*
+                #html
+            })
+            .into()
+        }
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+// `#[derive(Template)]` with `#[template(path = "page.pug")]` implements
+// `pugrs::Template`, the path being relative to the crate root. Templates
+// have no expressions, so the fields of the type are not referred to or
+// type-checked.
+#[proc_macro_derive(Template, attributes(template))]
+pub fn derive_template(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_template(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_template(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let path = template_path(input)?;
+    let file = crate_dir().join(path.value());
+    let name = file.to_string_lossy().into_owned();
+    let source = input::read_file(&name, None)
+        .map_err(|e| syn::Error::new(path.span(), format!("cannot read `{}`: {}", name, e)))?;
+    let (html, deps) = compile(source.get_text(), &file, Some(&name), path.span())?;
+    let deps = deps.iter().map(|dep| dep.to_string_lossy());
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::pugrs::Template for #ident #ty_generics #where_clause {
+            fn render_into(&self, w: &mut dyn std::fmt::Write) -> std::fmt::Result {
+                // Compile again when a template changes
+                #(const _: &[u8] = include_bytes!(#deps);)*
+                w.write_str(#html)
+            }
+        }
+    })
+}
+
+fn template_path(input: &DeriveInput) -> syn::Result<LitStr> {
+    let mut path = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("template")) {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("path") {
+                return Err(meta.error("unknown template option, expected `path`"));
+            }
+            path = Some(meta.value()?.parse()?);
+            Ok(())
+        })?;
+    }
+    path.ok_or_else(|| syn::Error::new(Span::call_site(), "missing `#[template(path = \"...\")]`"))
+}
+
+fn crate_dir() -> PathBuf {
+    PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap_or_default())
+}
+
+// Compile a template into its HTML and the files it reads. Errors are put
+// at `span`, with their line and column in the template, since a span
+// inside a string literal cannot be made on stable Rust.
+fn compile(
+    src: &str,
+    path: &Path,
+    name: Option<&str>,
+    span: Span,
+) -> syn::Result<(String, Vec<PathBuf>)> {
+    let output = Compiler::new()
+        .compile_with_path(src, Some(path))
+        .map_err(|errors| {
+            let mut errors = errors.iter().map(|e| {
+                let (line, col) = e.location(src);
+                let at = match name {
+                    Some(name) => format!("{}:{}:{}", name, line, col),
+                    None => format!("{}:{}", line, col),
+                };
+                let message = format!("{}: {}\n{}", at, e, e.snippet(src));
+                syn::Error::new(span, message)
+            });
+            let mut first = errors.next().unwrap();
+            for e in errors {
+                first.combine(e);
+            }
+            first
+        })?;
+    Ok((output.get_html().to_string(), output.get_deps().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macros_report_template_errors() {
+        let path = Path::new("page.pug");
+        let (html, _) = compile("p hi", path, None, Span::call_site()).unwrap();
+        assert_eq!(html, "<p>\n  hi\n</p>\n");
+
+        let errors = compile(
+            "p\n  a(\n  b #[i",
+            path,
+            Some("page.pug"),
+            Span::call_site(),
+        )
+        .unwrap_err();
+        let messages: Vec<String> = errors.into_iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "page.pug:2:4: missing `)` to close attributes\n2 |   a(\n  |    ^\n",
+                "page.pug:3:5: missing `]` to close interpolation\n3 |   b #[i\n  |     ^~\n",
+            ]
+        );
+    }
+}
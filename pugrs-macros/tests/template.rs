@@ -0,0 +1,31 @@
+extern crate pugrs;
+extern crate pugrs_macros;
+
+use pugrs::Template;
+use pugrs_macros::{pug, Template};
+
+#[derive(Template)]
+#[template(path = "tests/page.pug")]
+struct Page;
+
+#[derive(Template)]
+#[template(path = "tests/_item.pug")]
+struct Item<'a, T: Clone> {
+    _items: &'a [T],
+}
+
+const INLINE: &str = pug!("ul\n  li one\n  include tests/_item");
+
+#[test]
+fn macros_compile_templates() {
+    assert_eq!(
+        Page.render(),
+        "<!DOCTYPE html>\n<html lang=\"en\">\n  <body>\n    <h1>\n      Hello\n    </h1>\n    <p class=\"item\">\n      Included\n    </p>\n  </body>\n</html>\n"
+    );
+    let item = Item { _items: &[1] };
+    assert_eq!(item.render(), "<p class=\"item\">\n  Included\n</p>\n");
+    assert_eq!(
+        INLINE,
+        "<ul>\n  <li>\n    one\n  </li>\n  <p class=\"item\">\n    Included\n  </p>\n</ul>\n"
+    );
+}
@@ -0,0 +1,567 @@
+use cli::{Command, Dump, Options};
+use pugrs::compiler::include_path;
+use pugrs::input::{Encoding, Source};
+use pugrs::lex::{Lexer, TokenType};
+use pugrs::lint::Warning;
+use pugrs::parse::Parser;
+use pugrs::{fmt, from_html, input, js, json, Compiler, Error};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+// A template to compile and where its output goes, None for stdout
+pub struct Job {
+    pub input: String,
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub written: usize,
+    pub failed: usize,
+    // Files read by each job, in the order of the jobs
+    pub deps: Vec<Vec<PathBuf>>,
+}
+
+// Expand the inputs into jobs, walking directories for `.pug` files, or
+// `.html` files to convert
+pub fn collect_jobs(options: &Options) -> Result<Vec<Job>, String> {
+    let mut jobs = vec![];
+    for input in &options.inputs {
+        let path = Path::new(input);
+        if input == "-" || !path.is_dir() {
+            jobs.push(Job {
+                input: input.to_string(),
+                output: output_path(options, input),
+            });
+            continue;
+        }
+        let out_dir = options.out_dir.as_ref().map(Path::new);
+        if out_dir.is_none() && options.writes_output() {
+            return Err(format!(
+                "--out-dir is required to compile directory `{}`",
+                input
+            ));
+        }
+        let mut files = vec![];
+        walk(path, options.command, &mut files).map_err(|e| format!("{}: {}", input, e))?;
+        if options.command == Command::Compile {
+            // Templates included by others are parts of those pages
+            let included = included_files(&files, options.encoding);
+            files.retain(|file| !included.contains(&canonical(file)));
+        }
+        for file in files {
+            let relative = file.strip_prefix(path).unwrap();
+            let output = out_dir.map(|dir| {
+                dir.join(relative).with_file_name(output_name(
+                    options,
+                    &file.file_stem().unwrap().to_string_lossy(),
+                ))
+            });
+            jobs.push(Job {
+                input: file.to_string_lossy().into_owned(),
+                output,
+            });
+        }
+    }
+    Ok(jobs)
+}
+
+// Collect the files `command` reads under `dir` in a stable order. Partials,
+// named with a leading `_`, are only formatted since they are not pages of
+// their own.
+fn walk(dir: &Path, command: Command, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let extensions: &[&str] = match command {
+        Command::FromHtml => &["html", "htm"],
+        _ => &["pug"],
+    };
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if (name.starts_with('_') && command != Command::Fmt) || name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            walk(&path, command, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|e| extensions.iter().any(|&x| e == x))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// The templates that `files` include, directly or through other includes.
+// Files that cannot be read are left to fail when they are compiled.
+fn included_files(files: &[PathBuf], encoding: Option<&'static Encoding>) -> HashSet<PathBuf> {
+    let mut included = HashSet::new();
+    let mut read = HashSet::new();
+    let mut queue = files.to_vec();
+    while let Some(file) = queue.pop() {
+        if !read.insert(canonical(&file)) {
+            continue;
+        }
+        let source = match input::read_file(&file.to_string_lossy(), encoding) {
+            Ok(source) => source,
+            Err(_) => continue,
+        };
+        let mut lexer = Lexer::new(source.get_text().to_string());
+        lexer.tokenize();
+        let dir = file.parent().unwrap_or_else(|| Path::new(""));
+        for token in lexer.get_tokens() {
+            if let TokenType::Include(path) = token.get_type() {
+                let path = include_path(dir, path);
+                if path.extension().is_some_and(|e| e == "pug") {
+                    included.insert(canonical(&path));
+                    queue.push(path);
+                }
+            }
+        }
+    }
+    included
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn output_name(options: &Options, stem: &str) -> String {
+    match &options.extension[..] {
+        "" => stem.to_string(),
+        extension => format!("{}.{}", stem, extension),
+    }
+}
+
+// Where to write the output of `input`, or None for stdout
+fn output_path(options: &Options, input: &str) -> Option<PathBuf> {
+    if let Some(ref output) = options.output {
+        return Some(PathBuf::from(output));
+    }
+    let dir = options.out_dir.as_ref()?;
+    let stem = match input {
+        "-" => "stdin".to_string(),
+        _ => Path::new(input).file_stem()?.to_string_lossy().into_owned(),
+    };
+    Some(Path::new(dir).join(output_name(options, &stem)))
+}
+
+// Compile the jobs on `options.threads` threads. Whatever order they finish
+// in, the output and errors of each job are printed in the order of `jobs`.
+pub fn run(compiler: &Compiler, options: &Options, jobs: &[Job]) -> Summary {
+    run_with(compiler, options, jobs, |_, report| report.print())
+}
+
+// Run the jobs, passing each report to `print` with the index of its job
+fn run_with<F>(compiler: &Compiler, options: &Options, jobs: &[Job], mut print: F) -> Summary
+where
+    F: FnMut(usize, &Report) -> bool,
+{
+    let mut summary = Summary::default();
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..options.threads.min(jobs.len()) {
+            let tx = tx.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= jobs.len() {
+                    break;
+                }
+                let report = compile_job(compiler, options, &jobs[i]);
+                if tx.send((i, report)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        // Reports finished ahead of their turn wait here
+        let mut pending = BTreeMap::new();
+        let mut printed = 0;
+        for (i, report) in rx {
+            pending.insert(i, report);
+            while let Some(report) = pending.remove(&printed) {
+                summary.deps.push(report.deps.clone());
+                if print(printed, &report) {
+                    if report.written {
+                        summary.written += 1;
+                    }
+                } else {
+                    summary.failed += 1;
+                }
+                printed += 1;
+            }
+        }
+    });
+    summary
+}
+
+// What compiling a job printed, kept until it is the job's turn
+pub struct Report {
+    ok: bool,
+    // Whether a file was written
+    written: bool,
+    stdout: String,
+    stderr: String,
+    pub deps: Vec<PathBuf>,
+}
+
+impl Report {
+    fn failed(stderr: String) -> Report {
+        Report {
+            ok: false,
+            written: false,
+            stdout: "".to_string(),
+            stderr,
+            deps: vec![],
+        }
+    }
+
+    // Print the output and errors, returning whether the job succeeded
+    pub fn print(&self) -> bool {
+        eprint!("{}", self.stderr);
+        if let Err(e) = io::stdout().write_all(self.stdout.as_bytes()) {
+            eprintln!("<stdout>: error: {}", e);
+            return false;
+        }
+        self.ok
+    }
+}
+
+// Compile one template and write the output to its file. Output for stdout
+// and errors are returned in the report.
+pub fn compile_job(compiler: &Compiler, options: &Options, job: &Job) -> Report {
+    let (name, read) = match &job.input[..] {
+        "-" => ("<stdin>", input::read_stdin(options.encoding)),
+        input => (input, input::read_file(input, options.encoding)),
+    };
+    let source = match read {
+        Ok(source) => source,
+        Err(e) => return Report::failed(format!("{}: error: {}\n", name, e)),
+    };
+    let src = source.get_text();
+    match options.command {
+        Command::Fmt => return format(name, &source, job, options),
+        Command::FromHtml => return convert(name, src, job),
+        Command::Compile | Command::Lsp => (),
+    }
+    if let Some(what) = options.dump {
+        return dump(name, src, what);
+    }
+
+    let path = match &job.input[..] {
+        "-" => None,
+        input => Some(Path::new(input)),
+    };
+    let output = match compiler.compile_with_deps(src, path) {
+        Ok(output) => output,
+        Err((errors, deps)) => {
+            let mut report = Report::failed(format_errors(name, src, &errors));
+            // The files read up to the errors, the broken one included
+            report.deps = deps;
+            return report;
+        }
+    };
+    let deps = output.get_deps().to_vec();
+    let warnings = format_warnings(name, src, output.get_warnings());
+    if options.check {
+        return Report {
+            ok: true,
+            written: false,
+            stdout: "".to_string(),
+            stderr: warnings,
+            deps,
+        };
+    }
+    if options.deps {
+        // List the files read instead of writing the output, as a rule for
+        // the template since several may be listed
+        let stdout = dep_rule(&job.input, &deps);
+        return Report {
+            ok: true,
+            written: false,
+            stdout,
+            stderr: warnings,
+            deps,
+        };
+    }
+    let text = match options.js {
+        true => js::generate(output.get_nodes()),
+        false => output.get_html().to_string(),
+    };
+    let stdout = match job.output {
+        Some(ref path) => {
+            if let Err(e) = write_file(path, &text) {
+                let error = format!("{}: error: {}\n", path.display(), e);
+                return Report::failed(warnings + &error);
+            }
+            if let Some(source_map) = output.get_source_map() {
+                let mut map = path.clone().into_os_string();
+                map.push(".map");
+                let file = path.file_name().unwrap_or_default().to_string_lossy();
+                if let Err(e) = fs::write(&map, source_map.to_json(&file)) {
+                    let error = format!("{}: error: {}\n", Path::new(&map).display(), e);
+                    return Report::failed(warnings + &error);
+                }
+            }
+            "".to_string()
+        }
+        None => text,
+    };
+    Report {
+        ok: true,
+        written: job.output.is_some(),
+        stdout,
+        stderr: warnings,
+        deps,
+    }
+}
+
+// Print the tokens or nodes of a template as JSON, with any error on stderr
+fn dump(name: &str, src: &str, what: Dump) -> Report {
+    let mut lexer = Lexer::new(src.to_string());
+    lexer.tokenize();
+    let mut parser = Parser::new(lexer.get_tokens());
+    let json = match what {
+        Dump::Tokens => json::tokens_to_json(&lexer.get_tokens()),
+        Dump::Ast => json::nodes_to_json(&parser.parse()),
+    };
+    let mut errors = lexer.get_errors().to_vec();
+    errors.extend_from_slice(parser.get_errors());
+    errors.sort_by_key(|e| e.get_span());
+    Report {
+        ok: errors.is_empty(),
+        written: false,
+        stdout: json + "\n",
+        stderr: format_errors(name, src, &errors),
+        deps: vec![],
+    }
+}
+
+// Rewrite a template in the canonical style, in its own encoding, or print
+// it when read from stdin. With --check, a template that would change fails.
+fn format(name: &str, source: &Source, job: &Job, options: &Options) -> Report {
+    let src = source.get_text();
+    let formatted = match fmt::format(src) {
+        Ok(formatted) => formatted,
+        Err(errors) => return Report::failed(format_errors(name, src, &errors)),
+    };
+    let changed = formatted != src;
+    if options.check && changed {
+        return Report::failed(format!("{}: error: not formatted\n", name));
+    }
+    let mut report = Report {
+        ok: true,
+        written: false,
+        stdout: "".to_string(),
+        stderr: "".to_string(),
+        deps: vec![],
+    };
+    if options.check {
+        return report;
+    }
+    if job.input == "-" {
+        report.stdout = formatted;
+    } else if changed {
+        let (bytes, _, _) = source.get_encoding().encode(&formatted);
+        if let Err(e) = fs::write(&job.input, bytes) {
+            return Report::failed(format!("{}: error: {}\n", name, e));
+        }
+        report.written = true;
+    }
+    report
+}
+
+// Convert an HTML file to pug, written where compiled HTML would be
+fn convert(name: &str, src: &str, job: &Job) -> Report {
+    let pug = match from_html::convert(src) {
+        Ok(pug) => pug,
+        Err(errors) => return Report::failed(format_errors(name, src, &errors)),
+    };
+    let mut report = Report {
+        ok: true,
+        written: false,
+        stdout: "".to_string(),
+        stderr: "".to_string(),
+        deps: vec![],
+    };
+    match job.output {
+        Some(ref path) => {
+            if let Err(e) = write_file(path, &pug) {
+                return Report::failed(format!("{}: error: {}\n", path.display(), e));
+            }
+            report.written = true;
+        }
+        None => report.stdout = pug,
+    }
+    report
+}
+
+fn format_errors(name: &str, src: &str, errors: &[Error]) -> String {
+    errors
+        .iter()
+        .map(|e| diagnostic(name, src, "error", e, &e.to_string()))
+        .collect()
+}
+
+fn format_warnings(name: &str, src: &str, warnings: &[Warning]) -> String {
+    warnings
+        .iter()
+        .map(|w| diagnostic(name, src, "warning", w.get_error(), &w.to_string()))
+        .collect()
+}
+
+// `name:line:col: kind: message` followed by the line, underlined
+fn diagnostic(name: &str, src: &str, kind: &str, e: &Error, message: &str) -> String {
+    let (line, col) = e.location(src);
+    format!(
+        "{}:{}:{}: {}: {}\n{}",
+        name,
+        line,
+        col,
+        kind,
+        message,
+        e.snippet(src)
+    )
+}
+
+// Write a Make depfile with a rule for the output file of each job, or for
+// `-` when the output goes to stdout
+pub fn write_depfile(path: &str, jobs: &[Job], deps: &[Vec<PathBuf>]) -> io::Result<()> {
+    let mut rules = "".to_string();
+    for (job, deps) in jobs.iter().zip(deps) {
+        let target = match job.output {
+            Some(ref output) => output.to_string_lossy(),
+            None => "-".into(),
+        };
+        if !deps.is_empty() {
+            rules.push_str(&dep_rule(&target, deps));
+        }
+    }
+    write_file(Path::new(path), &rules)
+}
+
+// `target: deps` in Make syntax
+fn dep_rule(target: &str, deps: &[PathBuf]) -> String {
+    let mut rule = escape_make(target);
+    rule.push(':');
+    for dep in deps {
+        rule.push(' ');
+        rule.push_str(&escape_make(&dep.to_string_lossy()));
+    }
+    rule.push('\n');
+    rule
+}
+
+fn escape_make(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace('#', "\\#")
+        .replace(' ', "\\ ")
+}
+
+fn write_file(path: &Path, html: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn directories_skip_included_templates() {
+        let dir = env::temp_dir().join(format!("pugrs-walk-test-{}", process::id()));
+        fs::create_dir_all(dir.join("blog")).unwrap();
+        fs::write(dir.join("index.pug"), "body\n  include nav").unwrap();
+        fs::write(dir.join("nav.pug"), "nav").unwrap();
+        fs::write(dir.join("_layout.pug"), "include footer.pug").unwrap();
+        fs::write(dir.join("footer.pug"), "footer").unwrap();
+        fs::write(
+            dir.join("blog/post.pug"),
+            "include ../_layout\ninclude ad.html",
+        )
+        .unwrap();
+        fs::write(dir.join("blog/ad.html"), "<b>ad</b>").unwrap();
+
+        let input = dir.to_string_lossy().into_owned();
+        let args = vec![input, "--out-dir".to_string(), "dist".to_string()];
+        let jobs = collect_jobs(&Options::parse(args.into_iter()).unwrap()).unwrap();
+        let inputs: Vec<&str> = jobs.iter().map(|job| &job.input[..]).collect();
+        let name = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        assert_eq!(inputs, vec![name("blog/post.pug"), name("index.pug")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_keep_job_order() {
+        let dir = env::temp_dir().join(format!("pugrs-order-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // The first job takes the longest, so that later ones finish first
+        let mut jobs = vec![];
+        for i in 0..8 {
+            let input = dir.join(format!("{}.pug", i));
+            let lines = if i == 0 { 20000 } else { 1 };
+            fs::write(&input, "p ok\n".repeat(lines) + "p(").unwrap();
+            jobs.push(Job {
+                input: input.to_string_lossy().into_owned(),
+                output: None,
+            });
+        }
+        let args = vec!["--check".to_string(), "-j".to_string(), "4".to_string()];
+        let options = Options::parse(args.into_iter()).unwrap();
+        let mut printed = vec![];
+        let summary = run_with(&Compiler::new(), &options, &jobs, |i, report| {
+            assert!(report.stderr.starts_with(&jobs[i].input));
+            printed.push(i);
+            report.ok
+        });
+        assert_eq!(printed, (0..8).collect::<Vec<_>>());
+        assert_eq!(summary.failed, 8);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn depfile_rules() {
+        let jobs = vec![
+            Job {
+                input: "src/a b.pug".to_string(),
+                output: Some(PathBuf::from("dist/a b.html")),
+            },
+            Job {
+                input: "src/$c.pug".to_string(),
+                output: Some(PathBuf::from("dist/$c#.html")),
+            },
+            Job {
+                input: "src/d.pug".to_string(),
+                output: None,
+            },
+        ];
+        let deps = vec![
+            vec![PathBuf::from("src/a b.pug"), PathBuf::from("src/_nav.pug")],
+            vec![PathBuf::from("src/$c.pug")],
+            vec![PathBuf::from("src/d.pug")],
+        ];
+        let path = env::temp_dir().join(format!("pugrs-depfile-test-{}.d", process::id()));
+        write_depfile(&path.to_string_lossy(), &jobs, &deps).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "dist/a\\ b.html: src/a\\ b.pug src/_nav.pug\ndist/$$c\\#.html: src/$$c.pug\n-: src/d.pug\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+use pugrs::input;
+use pugrs::input::Encoding;
+use pugrs::lint::{Level, Rule};
+use std::thread;
+
+pub const USAGE: &str = "usage: pugrs [options] [FILE|DIR...]
+       pugrs fmt [--check] [FILE|DIR...]
+       pugrs from-html [options] [FILE|DIR...]
+       pugrs lsp [--validate] [--allow|--warn|--deny RULE...]
+
+Compiles pug templates into HTML. Reads stdin when no FILE or `-` is given.
+A DIR is searched for .pug files, skipping partials named `_*` and files
+other templates include, and its structure is mirrored under --out-dir.
+
+`fmt` rewrites templates in the canonical style, partials included, or
+with --check lists the templates it would change.
+
+`from-html` converts .html files into pug templates.
+
+`lsp` serves the Language Server Protocol on stdin and stdout for editors.
+
+options:
+  -o, --out FILE      write the output to FILE
+  --out-dir DIR       write each output to DIR/NAME.EXT
+  --extension EXT     extension of files written to --out-dir (default: html,
+                      js with --js or pug for from-html)
+  --encoding LABEL    read templates without a BOM in LABEL, e.g. shift_jis
+  --check             report errors without writing any output
+  --dump WHAT         print the `tokens` or `ast` of each template as JSON
+  --deps              list the files each template reads instead of compiling
+  --depfile FILE      also write a Make depfile listing the files read
+  --validate          report elements misplaced by the HTML content models
+  --source-map        also write a source map to OUTPUT.map for each output
+  --js                write a JavaScript module exporting `template(locals)`
+  --allow RULE        don't report lint RULE, or all lints with `warnings`
+  --warn RULE         report lint RULE as a warning (default)
+  --deny RULE         report lint RULE as an error
+  -j, --jobs N        compile on N threads (default: number of CPUs)
+  -w, --watch         compile again whenever a template changes
+  -h, --help          show this help";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command {
+    Compile,
+    Fmt,
+    FromHtml,
+    Lsp,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Dump {
+    Tokens,
+    Ast,
+}
+
+pub struct Options {
+    pub command: Command,
+    // `-` is stdin
+    pub inputs: Vec<String>,
+    pub output: Option<String>,
+    pub out_dir: Option<String>,
+    pub extension: String,
+    pub encoding: Option<&'static Encoding>,
+    pub check: bool,
+    pub dump: Option<Dump>,
+    pub deps: bool,
+    pub depfile: Option<String>,
+    pub validate: bool,
+    pub source_map: bool,
+    // Write JavaScript instead of HTML
+    pub js: bool,
+    // Lint levels in the order given
+    pub lints: Vec<(Rule, Level)>,
+    // Threads compiling templates in parallel
+    pub threads: usize,
+    pub watch: bool,
+    pub help: bool,
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut args = args.peekable();
+        let command = match args.peek().map(|arg| &arg[..]) {
+            Some("fmt") => {
+                args.next();
+                Command::Fmt
+            }
+            Some("from-html") => {
+                args.next();
+                Command::FromHtml
+            }
+            Some("lsp") => {
+                args.next();
+                Command::Lsp
+            }
+            _ => Command::Compile,
+        };
+        let mut options = Options {
+            command,
+            inputs: vec![],
+            output: None,
+            out_dir: None,
+            extension: match command {
+                Command::FromHtml => "pug".to_string(),
+                _ => "html".to_string(),
+            },
+            encoding: None,
+            check: false,
+            dump: None,
+            deps: false,
+            depfile: None,
+            validate: false,
+            source_map: false,
+            js: false,
+            lints: vec![],
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            watch: false,
+            help: false,
+        };
+        let mut extension = false;
+        while let Some(arg) = args.next() {
+            // "--name=value" is the same as "--name value"
+            let (name, mut value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => {
+                    (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value_of = |name: &str| -> Result<String, String> {
+                match value.take().or_else(|| args.next()) {
+                    Some(value) => Ok(value),
+                    None => Err(format!("{} requires a value", name)),
+                }
+            };
+            match &name[..] {
+                "-o" | "--out" => options.output = Some(value_of(&name)?),
+                "--out-dir" => options.out_dir = Some(value_of(&name)?),
+                "--extension" => {
+                    options.extension = value_of(&name)?.trim_start_matches('.').to_string();
+                    extension = true;
+                }
+                "--encoding" => {
+                    let label = value_of(&name)?;
+                    match input::encoding_for_label(&label) {
+                        Some(e) => options.encoding = Some(e),
+                        None => return Err(format!("unknown encoding `{}`", label)),
+                    }
+                }
+                "--check" => options.check = true,
+                "--dump" => {
+                    options.dump = match &value_of(&name)?[..] {
+                        "tokens" => Some(Dump::Tokens),
+                        "ast" => Some(Dump::Ast),
+                        what => return Err(format!("cannot dump `{}`", what)),
+                    }
+                }
+                "--deps" => options.deps = true,
+                "--depfile" => options.depfile = Some(value_of(&name)?),
+                "--validate" => options.validate = true,
+                "--source-map" => options.source_map = true,
+                "--js" => options.js = true,
+                "--allow" | "--warn" | "--deny" => {
+                    let level = match &name[..] {
+                        "--allow" => Level::Allow,
+                        "--warn" => Level::Warn,
+                        _ => Level::Deny,
+                    };
+                    let rules = match &value_of(&name)?[..] {
+                        "warnings" => Rule::ALL.to_vec(),
+                        rule => match Rule::from_name(rule) {
+                            Some(rule) => vec![rule],
+                            None => return Err(format!("unknown lint `{}`", rule)),
+                        },
+                    };
+                    options
+                        .lints
+                        .extend(rules.into_iter().map(|rule| (rule, level)));
+                }
+                "-j" | "--jobs" => {
+                    let n = value_of(&name)?;
+                    match n.parse() {
+                        Ok(n) if n > 0 => options.threads = n,
+                        _ => return Err(format!("invalid number of jobs `{}`", n)),
+                    }
+                }
+                "-w" | "--watch" => options.watch = true,
+                "-h" | "--help" => options.help = true,
+                "-" => options.inputs.push(arg),
+                _ if name.starts_with('-') => return Err(format!("unknown option `{}`", name)),
+                _ => options.inputs.push(arg),
+            }
+        }
+        if options.inputs.is_empty() {
+            options.inputs.push("-".to_string());
+        }
+        if options.output.is_some() && options.out_dir.is_some() {
+            return Err("-o and --out-dir cannot be used together".to_string());
+        }
+        if options.output.is_some() && options.inputs.len() > 1 {
+            return Err("-o cannot be used with more than one input".to_string());
+        }
+        if options.js && !extension {
+            options.extension = "js".to_string();
+        }
+        let compiles = options.dump.is_some() || options.deps || options.depfile.is_some();
+        if options.js && (options.command != Command::Compile || options.source_map) {
+            return Err("--js only compiles templates, without --source-map".to_string());
+        }
+        if options.source_map
+            && (options.command != Command::Compile
+                || !options.writes_output()
+                || options.output.is_none() && options.out_dir.is_none())
+        {
+            return Err("--source-map requires compiling to -o or --out-dir".to_string());
+        }
+        match options.command {
+            Command::Fmt
+                if compiles
+                    || options.output.is_some()
+                    || options.out_dir.is_some()
+                    || options.watch =>
+            {
+                return Err("fmt only takes --check, --encoding and -j".to_string());
+            }
+            Command::FromHtml if compiles || options.check || options.watch => {
+                return Err(
+                    "from-html only takes -o, --out-dir, --extension, --encoding and -j"
+                        .to_string(),
+                );
+            }
+            Command::Lsp
+                if compiles
+                    || options.inputs != ["-"]
+                    || options.output.is_some()
+                    || options.out_dir.is_some()
+                    || options.check
+                    || options.watch =>
+            {
+                return Err("lsp only takes --validate, --allow, --warn and --deny".to_string());
+            }
+            _ => (),
+        }
+        Ok(options)
+    }
+
+    // False when templates are formatted, only checked, dumped or their
+    // files listed
+    pub fn writes_output(&self) -> bool {
+        self.command != Command::Fmt && !self.check && self.dump.is_none() && !self.deps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn options_parse() {
+        let options = parse(&["a.pug", "--out-dir=dist", "--extension", ".htm"]).unwrap();
+        assert_eq!(options.inputs, vec!["a.pug"]);
+        assert_eq!(options.out_dir, Some("dist".to_string()));
+        assert_eq!(options.extension, "htm");
+
+        let options = parse(&["-o", "out.html"]).unwrap();
+        assert_eq!(options.inputs, vec!["-"]);
+        assert_eq!(options.output, Some("out.html".to_string()));
+
+        assert!(parse(&["--out"]).is_err());
+        assert!(parse(&["--nope"]).is_err());
+        assert!(parse(&["a.pug", "b.pug", "-o", "x"]).is_err());
+        assert!(parse(&["--encoding", "nope"]).is_err());
+        assert_eq!(parse(&["-j", "4"]).unwrap().threads, 4);
+        assert!(parse(&["-j", "0"]).is_err());
+        assert_eq!(parse(&["--dump=ast"]).unwrap().dump, Some(Dump::Ast));
+        assert!(parse(&["--dump", "html"]).is_err());
+        let options = parse(&["--deny=warnings", "--allow", "unknown-tag"]).unwrap();
+        assert_eq!(options.lints.len(), Rule::ALL.len() + 1);
+        assert_eq!(
+            options.lints.last(),
+            Some(&(Rule::UnknownTag, Level::Allow))
+        );
+        assert!(parse(&["--deny", "nope"]).is_err());
+        let options = parse(&["fmt", "--check", "fmt"]).unwrap();
+        assert_eq!(options.command, Command::Fmt);
+        assert_eq!(options.inputs, vec!["fmt"]);
+        assert!(parse(&["fmt", "--out-dir", "dist"]).is_err());
+        let options = parse(&["from-html", "a.html", "--out-dir", "src"]).unwrap();
+        assert_eq!(options.command, Command::FromHtml);
+        assert_eq!(options.extension, "pug");
+        assert!(parse(&["from-html", "--watch"]).is_err());
+        let options = parse(&["lsp", "--validate", "--deny", "warnings"]).unwrap();
+        assert_eq!(options.command, Command::Lsp);
+        assert!(parse(&["lsp", "a.pug"]).is_err());
+        assert!(
+            parse(&["a.pug", "-o", "a.html", "--source-map"])
+                .unwrap()
+                .source_map
+        );
+        assert!(parse(&["a.pug", "--source-map"]).is_err());
+        assert!(parse(&["--out-dir", "dist", "--check", "--source-map"]).is_err());
+        assert_eq!(
+            parse(&["--js", "--out-dir", "dist"]).unwrap().extension,
+            "js"
+        );
+        assert_eq!(
+            parse(&["--extension=mjs", "--js"]).unwrap().extension,
+            "mjs"
+        );
+        assert!(parse(&["fmt", "--js"]).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use parse::Node;
+use render;
+use render::Chunk;
+
+// Rust source of a function writing the HTML of a compiled template, for
+// build scripts, so that a template is not parsed when it is served:
+//
+//     pub fn NAME(w: &mut impl ::std::fmt::Write, _ctx: &CONTEXT) -> ::std::fmt::Result
+//
+// The nodes are rendered into string literals, one for each run of markup
+// and text between attribute values, which are escaped as they are written.
+// Templates have no expressions yet, so the context is not read.
+pub fn generate(nodes: &[Node], name: &str, context: &str) -> String {
+    let chunks = render::render_chunks(nodes);
+    let mut rust = "// Generated by pugrs, do not edit\n".to_string();
+    rust.push_str(&format!(
+        "pub fn {}(w: &mut impl ::std::fmt::Write, _ctx: &{}) -> ::std::fmt::Result {{\n",
+        name, context
+    ));
+    if chunks.iter().any(|chunk| matches!(chunk, Chunk::Attr(_))) {
+        rust.push_str(ESCAPE);
+    }
+    for chunk in &chunks {
+        match chunk {
+            Chunk::Html(html) => rust.push_str(&format!("    w.write_str({:?})?;\n", html)),
+            Chunk::Attr(value) => rust.push_str(&format!("    escape(w, {:?})?;\n", value)),
+        }
+    }
+    rust.push_str("    Ok(())\n}\n");
+    rust
+}
+
+// Writes an attribute value as `render::escape_attr` does
+const ESCAPE: &str = r#"    fn escape(w: &mut impl ::std::fmt::Write, value: &str) -> ::std::fmt::Result {
+        for (i, part) in value.split('"').enumerate() {
+            if i > 0 {
+                w.write_str("&quot;")?;
+            }
+            w.write_str(part)?;
+        }
+        Ok(())
+    }
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use Compiler;
+
+    #[test]
+    fn codegen_writes_function() {
+        let output = Compiler::new()
+            .compile_with_path("p(title='a \"b\"' lang=\"en\") Hi \"there\"\nbr", None)
+            .unwrap();
+        assert_eq!(
+            generate(output.get_nodes(), "render_page", "Page"),
+            r#"// Generated by pugrs, do not edit
+pub fn render_page(w: &mut impl ::std::fmt::Write, _ctx: &Page) -> ::std::fmt::Result {
+    fn escape(w: &mut impl ::std::fmt::Write, value: &str) -> ::std::fmt::Result {
+        for (i, part) in value.split('"').enumerate() {
+            if i > 0 {
+                w.write_str("&quot;")?;
+            }
+            w.write_str(part)?;
+        }
+        Ok(())
+    }
+    w.write_str("<p title=\"")?;
+    escape(w, "a \"b\"")?;
+    w.write_str("\" lang=\"")?;
+    escape(w, "en")?;
+    w.write_str("\">\n  Hi \"there\"\n</p>\n<br>\n")?;
+    Ok(())
+}
+"#
+        );
+    }
+
+    // Build the generated functions with rustc and check that they write
+    // the HTML of the compiler
+    #[test]
+    fn codegen_builds_and_runs() {
+        let mut corpus = vec!["p\n  | tab\there\u{1} \\ ページ".to_string()];
+        for entry in fs::read_dir("samples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "pug") {
+                corpus.push(fs::read_to_string(path).unwrap());
+            }
+        }
+        let dir = env::temp_dir().join(format!("pugrs-codegen-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut main = "pub struct Page;\n\nfn main() {\n".to_string();
+        let mut expected = "".to_string();
+        for (i, src) in corpus.iter().enumerate() {
+            let output = Compiler::new().compile_with_path(src, None).unwrap();
+            let name = format!("render_{}", i);
+            main.insert_str(0, &generate(output.get_nodes(), &name, "Page"));
+            main.push_str(&format!(
+                "    let mut html = String::new();\n    {}(&mut html, &Page).unwrap();\n    print!(\"{{}}\", html);\n",
+                name
+            ));
+            expected.push_str(output.get_html());
+        }
+        main.push_str("}\n");
+        fs::write(dir.join("main.rs"), main).unwrap();
+
+        let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let status = process::Command::new(rustc)
+            .arg("main.rs")
+            .args(["-o", "main", "-D", "warnings"])
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let run = process::Command::new(dir.join("main")).output().unwrap();
+        assert!(run.status.success());
+        assert_eq!(String::from_utf8(run.stdout).unwrap(), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
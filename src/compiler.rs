@@ -0,0 +1,644 @@
+use error::{Error, Result};
+use filter::{Cdata, Escape, Filter, Markdown, Plain};
+use input;
+use input::Encoding;
+use lex::Lexer;
+use lint::{Level, Linter, Rule, Warning};
+use log::debug;
+use parse::{FilterBlock, Include, Node, Parser};
+use render;
+use source_map::{Source, SourceMap};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use validate;
+
+// Compiles pug source into HTML with a set of named filters
+pub struct Compiler {
+    filters: HashMap<String, Box<dyn Filter>>,
+    encoding: Option<&'static Encoding>,
+    linter: Linter,
+    // Check elements against the HTML content models
+    validate: bool,
+    source_map: bool,
+    // Included templates by canonical path, parsed once for all the
+    // templates compiled with this compiler
+    includes: RwLock<HashMap<PathBuf, Arc<Parsed>>>,
+}
+
+// A template file as parsed, valid while its size and modification time are
+// those it was read with
+struct Parsed {
+    modified: Option<SystemTime>,
+    len: u64,
+    src: String,
+    nodes: Vec<Node>,
+    errors: Vec<Error>,
+}
+
+// The HTML of a template, the files read to compile it, lint warnings and
+// the source map when asked for
+pub struct Output {
+    html: String,
+    // The nodes rendered, with includes and filters replaced
+    nodes: Vec<Node>,
+    deps: Vec<PathBuf>,
+    warnings: Vec<Warning>,
+    source_map: Option<SourceMap>,
+}
+
+// What is collected while loading a template and its includes
+struct Context {
+    // Files being included, to detect an include of itself
+    stack: Vec<PathBuf>,
+    deps: Vec<PathBuf>,
+    warnings: Vec<Warning>,
+    // Ids of the elements linted so far, in the template and its includes
+    ids: HashSet<String>,
+    // Templates in the source map, and the offset of the spans of the one
+    // being loaded
+    sources: Vec<Source>,
+    offset: usize,
+}
+
+impl Context {
+    // The offset of the spans of a template, the same each time it is
+    // included
+    fn add_source(&mut self, name: &str, src: &str) -> usize {
+        if let Some(source) = self.sources.iter().find(|s| s.get_name() == name) {
+            return source.get_offset();
+        }
+        let offset = self.sources.last().map_or(0, Source::get_end);
+        self.sources
+            .push(Source::new(name.to_string(), src.to_string(), offset));
+        offset
+    }
+}
+
+impl Output {
+    pub fn get_html(&self) -> &str {
+        &self.html
+    }
+    // For generating code that writes the HTML
+    pub fn get_nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+    // The template first, then included files in the order they are read
+    pub fn get_deps(&self) -> &[PathBuf] {
+        &self.deps
+    }
+    pub fn get_warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+    pub fn get_source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+}
+
+// The file `include path` reads from a template in `dir`, `.pug` unless the
+// path has an extension
+pub fn include_path(dir: &Path, path: &str) -> PathBuf {
+    let mut path = dir.join(path);
+    if path.extension().is_none() {
+        path.set_extension("pug");
+    }
+    path
+}
+
+// The nodes of `src` with the lexer and parser errors
+fn parse(src: &str) -> (Vec<Node>, Vec<Error>) {
+    let mut lexer = Lexer::new(src.to_string());
+    lexer.tokenize();
+    let tokens = lexer.get_tokens();
+    debug!("Getting tokens done!");
+    let mut parser = Parser::new(tokens);
+    let nodes = parser.parse();
+    let mut errors = lexer.get_errors().to_vec();
+    errors.extend_from_slice(parser.get_errors());
+    (nodes, errors)
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        let mut compiler = Compiler {
+            filters: HashMap::new(),
+            encoding: None,
+            linter: Linter::new(),
+            validate: false,
+            source_map: false,
+            includes: RwLock::new(HashMap::new()),
+        };
+        compiler.register_filter("plain", Plain);
+        compiler.register_filter("escape", Escape);
+        compiler.register_filter("cdata", Cdata);
+        compiler.register_filter("markdown", Markdown);
+        compiler
+    }
+
+    // Register a filter used as `:name`, replacing any filter of that name
+    pub fn register_filter<F: Filter + 'static>(&mut self, name: &str, filter: F) {
+        self.filters.insert(name.to_string(), Box::new(filter));
+    }
+
+    // Encoding of included files without a BOM, UTF-8 by default
+    pub fn set_encoding(&mut self, encoding: Option<&'static Encoding>) {
+        self.encoding = encoding;
+    }
+
+    // Allow, warn or deny a lint rule, all rules warn by default
+    pub fn set_lint_level(&mut self, rule: Rule, level: Level) {
+        self.linter.set_level(rule, level);
+    }
+
+    pub fn set_validate(&mut self, validate: bool) {
+        self.validate = validate;
+    }
+
+    // Map the HTML back to the templates in `Output::get_source_map`
+    pub fn set_source_map(&mut self, source_map: bool) {
+        self.source_map = source_map;
+    }
+
+    pub fn compile(&self, src: &str) -> result::Result<String, Vec<Error>> {
+        self.compile_with_path(src, None).map(|output| output.html)
+    }
+
+    // Compile a template read from `path`, resolving includes relative to
+    // it, or to the current directory without a path.
+    pub fn compile_with_path(
+        &self,
+        src: &str,
+        path: Option<&Path>,
+    ) -> result::Result<Output, Vec<Error>> {
+        self.compile_with_deps(src, path)
+            .map_err(|(errors, _)| errors)
+    }
+
+    // `compile_with_path`, returning with the errors the files read until
+    // then, the one that failed to compile included
+    pub fn compile_with_deps(
+        &self,
+        src: &str,
+        path: Option<&Path>,
+    ) -> result::Result<Output, (Vec<Error>, Vec<PathBuf>)> {
+        let mut context = Context {
+            stack: vec![],
+            deps: vec![],
+            warnings: vec![],
+            ids: HashSet::new(),
+            sources: vec![],
+            offset: 0,
+        };
+        if let Some(path) = path {
+            context.deps.push(path.to_path_buf());
+            context.stack.push(canonical(path));
+        }
+        if self.source_map {
+            let name = path.map_or("".into(), Path::to_string_lossy);
+            context.add_source(&name, src);
+        }
+        let dir = path.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+        let nodes = match self.load(src, dir, &mut context) {
+            Ok(nodes) => nodes,
+            Err(errors) => return Err((errors, context.deps)),
+        };
+        debug!("-------------- generate HTML! ---------------");
+        let (html, source_map) = match self.source_map {
+            true => {
+                let (html, mappings) = render::render_with_mappings(&nodes);
+                let source_map = SourceMap::new(&html, context.sources, &mappings);
+                (html, Some(source_map))
+            }
+            false => (render::render(&nodes), None),
+        };
+        Ok(Output {
+            html,
+            nodes,
+            deps: context.deps,
+            warnings: context.warnings,
+            source_map,
+        })
+    }
+
+    // Parse and lint `src`, read its includes and apply its filters. Errors
+    // point into `src`, including errors in included files.
+    fn load(
+        &self,
+        src: &str,
+        dir: &Path,
+        context: &mut Context,
+    ) -> result::Result<Vec<Node>, Vec<Error>> {
+        let (nodes, errors) = parse(src);
+        self.load_parsed(nodes, errors, dir, context)
+    }
+
+    // Lint, include and filter nodes parsed with `errors`
+    fn load_parsed(
+        &self,
+        mut nodes: Vec<Node>,
+        mut errors: Vec<Error>,
+        dir: &Path,
+        context: &mut Context,
+    ) -> result::Result<Vec<Node>, Vec<Error>> {
+        for warning in self.linter.check_with_ids(&nodes, &mut context.ids) {
+            match self.linter.get_level(warning.get_rule()) {
+                Level::Deny => errors.push(warning.into_error()),
+                _ => context.warnings.push(warning),
+            }
+        }
+        if self.validate {
+            errors.extend(validate::validate(&nodes));
+        }
+        errors.sort_by_key(|e| e.get_span());
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        nodes = self.resolve_includes(nodes, dir, context, &mut errors);
+        self.apply_filters(&mut nodes, &mut errors);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(nodes)
+    }
+
+    // Replace includes with the nodes of the included files
+    fn resolve_includes(
+        &self,
+        nodes: Vec<Node>,
+        dir: &Path,
+        context: &mut Context,
+        errors: &mut Vec<Error>,
+    ) -> Vec<Node> {
+        let mut resolved = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            match node {
+                Node::Include(include) => match self.include(&include, dir, context) {
+                    Ok(nodes) => resolved.extend(nodes),
+                    Err(e) => errors.extend(e),
+                },
+                Node::Element(mut e) => {
+                    let children = mem::take(e.get_children_mut());
+                    *e.get_children_mut() = self.resolve_includes(children, dir, context, errors);
+                    resolved.push(Node::Element(e));
+                }
+                node => resolved.push(node),
+            }
+        }
+        resolved
+    }
+
+    // Read an included file. Pug templates are compiled, any other file is
+    // included as it is.
+    fn include(
+        &self,
+        include: &Include,
+        dir: &Path,
+        context: &mut Context,
+    ) -> result::Result<Vec<Node>, Vec<Error>> {
+        let (start, end) = include.get_span();
+        let path = include_path(dir, include.get_path());
+        let name = path.to_string_lossy().into_owned();
+        if !context.deps.contains(&path) {
+            context.deps.push(path.clone());
+        }
+        if context.stack.contains(&canonical(&path)) {
+            let message = format!("`{}` includes itself", name);
+            return Err(vec![Error::new(message, start, end)]);
+        }
+        let cannot_include = |e: io::Error| {
+            vec![Error::new(
+                format!("cannot include `{}`: {}", name, e),
+                start,
+                end,
+            )]
+        };
+        if path.extension().is_some_and(|e| e != "pug") {
+            let source = input::read_file(&name, self.encoding).map_err(cannot_include)?;
+            let text = source.get_text().trim_end_matches('\n').to_string();
+            return Ok(vec![Node::Raw(text, (start, end))]);
+        }
+        let parsed = self.parse_file(&path).map_err(cannot_include)?;
+        let src = &parsed.src[..];
+
+        context.stack.push(canonical(&path));
+        let warnings = context.warnings.len();
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let parent = context.offset;
+        if self.source_map {
+            context.offset = context.add_source(&name, src);
+        }
+        let nodes = self.load_parsed(parsed.nodes.clone(), parsed.errors.clone(), dir, context);
+        let offset = mem::replace(&mut context.offset, parent);
+        context.stack.pop();
+        // Report problems at the include with their place in the file
+        let relocate = |e: &Error| {
+            let (line, col) = e.location(src);
+            let message = format!("{}:{}:{}: {}", name, line, col, e);
+            Error::new(message, start, end)
+        };
+        for warning in &mut context.warnings[warnings..] {
+            *warning = Warning::new(warning.get_rule(), relocate(warning.get_error()));
+        }
+        let mut nodes = nodes.map_err(|errors| errors.iter().map(relocate).collect::<Vec<_>>())?;
+        // Nodes of included files of this one have been moved relative to
+        // it already
+        for node in &mut nodes {
+            node.shift_spans(offset as isize - parent as isize);
+        }
+        Ok(nodes)
+    }
+
+    // Parse a template file, or take it from the cache when it has not
+    // changed since it was parsed
+    fn parse_file(&self, path: &Path) -> io::Result<Arc<Parsed>> {
+        let metadata = fs::metadata(path)?;
+        let (modified, len) = (metadata.modified().ok(), metadata.len());
+        let key = canonical(path);
+        if let Some(parsed) = self.includes.read().unwrap().get(&key) {
+            if parsed.modified.is_some() && parsed.modified == modified && parsed.len == len {
+                return Ok(parsed.clone());
+            }
+        }
+        let source = input::read_file(&path.to_string_lossy(), self.encoding)?;
+        let src = source.get_text().to_string();
+        let (nodes, errors) = parse(&src);
+        let parsed = Arc::new(Parsed {
+            modified,
+            len,
+            src,
+            nodes,
+            errors,
+        });
+        self.includes.write().unwrap().insert(key, parsed.clone());
+        Ok(parsed)
+    }
+
+    // Replace filter blocks with their output
+    fn apply_filters(&self, nodes: &mut [Node], errors: &mut Vec<Error>) {
+        for node in nodes.iter_mut() {
+            let html = match node {
+                Node::Filter(block) => match self.run_filters(block) {
+                    Ok(html) => html,
+                    Err(e) => {
+                        errors.push(e);
+                        continue;
+                    }
+                },
+                Node::Element(e) => {
+                    self.apply_filters(e.get_children_mut(), errors);
+                    continue;
+                }
+                _ => continue,
+            };
+            *node = Node::Raw(html, node.get_span().unwrap());
+        }
+    }
+
+    // Apply chained filters from the innermost, `:escape:markdown` escapes
+    // the output of markdown
+    fn run_filters(&self, block: &FilterBlock) -> Result<String> {
+        let (start, end) = block.get_span();
+        let mut text = block.get_text().to_string();
+        for (name, opts) in block.get_filters().iter().rev() {
+            let filter = match self.filters.get(name) {
+                Some(filter) => filter,
+                None => {
+                    return Err(Error::new(
+                        format!("unknown filter `:{}`", name),
+                        start,
+                        end,
+                    ));
+                }
+            };
+            text = filter
+                .apply(&text, opts)
+                .map_err(|e| e.with_span(start, end))?;
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse::Attrs;
+    use std::env;
+    use std::process;
+
+    struct Upper;
+
+    impl Filter for Upper {
+        fn apply(&self, text: &str, _opts: &Attrs) -> Result<String> {
+            Ok(text.to_uppercase())
+        }
+    }
+
+    #[test]
+    fn compiler_applies_filters() {
+        let mut compiler = Compiler::new();
+        compiler.register_filter("upper", Upper);
+        let html = compiler
+            .compile("div\n  :escape:upper <b>\n  :markdown\n    *hi*")
+            .unwrap();
+        assert_eq!(html, "<div>\n  &lt;B&gt;\n  <p><em>hi</em></p>\n</div>\n");
+    }
+
+    #[test]
+    fn compiler_renders_comments() {
+        let html = Compiler::new()
+            .compile("div\n\n  // one\n  //- two\n  //\n    three\n      four")
+            .unwrap();
+        assert_eq!(
+            html,
+            "<div>\n  <!-- one-->\n  <!--\n    three\n      four\n  -->\n</div>\n"
+        );
+    }
+
+    #[test]
+    fn compiler_includes_files() {
+        let dir = env::temp_dir().join(format!("pugrs-include-test-{}", process::id()));
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(dir.join("parts/_nav.pug"), "nav\n  include links.html\n").unwrap();
+        fs::write(dir.join("parts/links.html"), "<a href=\"/\">top</a>\n").unwrap();
+        fs::write(dir.join("_loop.pug"), "p\n  include _loop").unwrap();
+        fs::write(dir.join("_bad.pug"), "p\n\t a").unwrap();
+
+        let page = dir.join("page.pug");
+        let src = "body\n  include parts/_nav\n  p hi";
+        let output = Compiler::new().compile_with_path(src, Some(&page)).unwrap();
+        assert_eq!(
+            output.get_html(),
+            "<body>\n  <nav>\n    <a href=\"/\">top</a>\n  </nav>\n  <p>\n    hi\n  </p>\n</body>\n"
+        );
+        let deps: Vec<PathBuf> = vec![
+            page.clone(),
+            dir.join("parts/_nav.pug"),
+            dir.join("parts/links.html"),
+        ];
+        assert_eq!(output.get_deps(), &deps[..]);
+
+        let errors = Compiler::new()
+            .compile_with_path("include _loop\ninclude _bad\ninclude _none", Some(&page))
+            .err()
+            .unwrap();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        let name = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        assert_eq!(
+            messages,
+            vec![
+                format!(
+                    "{}:2:3: `{}` includes itself",
+                    name("_loop.pug"),
+                    name("_loop.pug")
+                ),
+                format!(
+                    "{}:2:1: mixed tabs and spaces in indentation",
+                    name("_bad.pug")
+                ),
+                format!(
+                    "cannot include `{}`: No such file or directory (os error 2)",
+                    name("_none.pug")
+                ),
+            ]
+        );
+        assert_eq!(errors[0].get_span(), (0, 13));
+
+        let (_, deps) = Compiler::new()
+            .compile_with_deps("p\n  include parts/_nav\n  include _bad", Some(&page))
+            .err()
+            .unwrap();
+        let read = vec![
+            page.clone(),
+            dir.join("parts/_nav.pug"),
+            dir.join("parts/links.html"),
+            dir.join("_bad.pug"),
+        ];
+        assert_eq!(deps, read);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compiler_parses_includes_once() {
+        let dir = env::temp_dir().join(format!("pugrs-cache-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("_a.pug"), "p a").unwrap();
+
+        let compiler = Compiler::new();
+        let page = dir.join("page.pug");
+        let compile = |src| compiler.compile_with_path(src, Some(&page)).unwrap();
+        assert_eq!(compile("include _a").get_html(), "<p>\n  a\n</p>\n");
+        let parsed = compiler.includes.read().unwrap()[&canonical(&dir.join("_a.pug"))].clone();
+        assert_eq!(
+            compile("div\n  include _a").get_html(),
+            "<div>\n  <p>\n    a\n  </p>\n</div>\n"
+        );
+        let cached = compiler.includes.read().unwrap()[&canonical(&dir.join("_a.pug"))].clone();
+        assert!(Arc::ptr_eq(&parsed, &cached));
+
+        fs::write(dir.join("_a.pug"), "p changed").unwrap();
+        assert_eq!(compile("include _a").get_html(), "<p>\n  changed\n</p>\n");
+        assert_eq!(compiler.includes.read().unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compiler_lints_ids_across_includes() {
+        let dir = env::temp_dir().join(format!("pugrs-lint-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("_p.pug"), "#a\n#b").unwrap();
+
+        let output = Compiler::new()
+            .compile_with_path("#a\ninclude _p.pug", Some(&dir.join("m.pug")))
+            .unwrap();
+        let warnings: Vec<String> = output
+            .get_warnings()
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        let name = dir.join("_p.pug").to_string_lossy().into_owned();
+        assert_eq!(
+            warnings,
+            vec![format!("{}:1:1: duplicate id `a` [duplicate-id]", name)]
+        );
+        assert_eq!(output.get_warnings()[0].get_error().get_span(), (3, 17));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compiler_maps_includes() {
+        let dir = env::temp_dir().join(format!("pugrs-source-map-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("_a.pug"), "main\n  include _b").unwrap();
+        fs::write(dir.join("_b.pug"), "p b").unwrap();
+
+        let page = dir.join("page.pug");
+        let mut compiler = Compiler::new();
+        compiler.set_source_map(true);
+        let src = "body\n  include _b\n  include _a";
+        let output = compiler.compile_with_path(src, Some(&page)).unwrap();
+        assert_eq!(
+            output.get_html(),
+            "<body>\n  <p>\n    b\n  </p>\n  <main>\n    <p>\n      b\n    </p>\n  </main>\n</body>\n"
+        );
+        let map = output.get_source_map().unwrap();
+        let name = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        let names: Vec<&str> = map.get_sources().iter().map(|s| s.get_name()).collect();
+        assert_eq!(
+            names,
+            vec![name("page.pug"), name("_b.pug"), name("_a.pug")]
+        );
+        let lookup = |line, column| {
+            let (source, line, column) = map.lookup(line, column).unwrap();
+            (source.to_string(), line, column)
+        };
+        assert_eq!(lookup(1, 2), (name("_b.pug"), 0, 0));
+        assert_eq!(lookup(4, 2), (name("_a.pug"), 0, 0));
+        assert_eq!(lookup(6, 6), (name("_b.pug"), 0, 1));
+        assert_eq!(lookup(9, 0), (name("page.pug"), 0, 0));
+        assert!(Compiler::new()
+            .compile_with_path(src, Some(&page))
+            .unwrap()
+            .get_source_map()
+            .is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compiler_reports_all_errors() {
+        let src = "ul\n  li(a=\"1)\n  li ok\n    | a\n      | b\n  li #[b\n  :nope\np";
+        let errors = Compiler::new().compile(src).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "missing `)` to close attributes",
+                "missing closing quote",
+                "unexpected indentation",
+                "missing `]` to close interpolation",
+            ]
+        );
+    }
+
+    #[test]
+    fn compiler_reports_unknown_filter() {
+        let errors = Compiler::new().compile("p\n  :nope text").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "unknown filter `:nope`");
+        assert_eq!(errors[0].get_span(), (4, 14));
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::result;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Error {
+    message: String,
+    start: usize,
+    end: usize,
+}
+
+impl Error {
+    pub fn new(message: String, start: usize, end: usize) -> Error {
+        Error {
+            message,
+            start,
+            end,
+        }
+    }
+    pub fn get_span(&self) -> (usize, usize) {
+        (self.start, self.end)
+    }
+    // Point an error raised without source position at `start..end`
+    pub fn with_span(self, start: usize, end: usize) -> Error {
+        Error { start, end, ..self }
+    }
+    // 1-based line and column of the error start in `src`
+    pub fn location(&self, src: &str) -> (usize, usize) {
+        let before = &src[..self.start.min(src.len())];
+        let line = before.matches('\n').count() + 1;
+        let col = match before.rfind('\n') {
+            Some(i) => before[i + 1..].chars().count() + 1,
+            None => before.chars().count() + 1,
+        };
+        (line, col)
+    }
+    // The line of `src` where the error starts, underlined up to the error
+    // end or the end of that line
+    pub fn snippet(&self, src: &str) -> String {
+        let start = self.start.min(src.len());
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let line = &src[line_start..line_end];
+        let (number, _) = self.location(src);
+        let gutter = number.to_string().len();
+        // Keep tabs so that the marker lines up with the text
+        let pad: String = src[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = src[start..self.end.clamp(start, line_end)].chars().count();
+        format!(
+            "{} | {}\n{} | {}^{}\n",
+            number,
+            line,
+            " ".repeat(gutter),
+            pad,
+            "~".repeat(width.saturating_sub(1))
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_location() {
+        let src = "html\n  body/\n    | ページ";
+        assert_eq!(Error::new("".to_string(), 0, 1).location(src), (1, 1));
+        assert_eq!(Error::new("".to_string(), 11, 12).location(src), (2, 7));
+        let last = src.rfind('ジ').unwrap();
+        assert_eq!(Error::new("".to_string(), last, last).location(src), (3, 9));
+    }
+
+    #[test]
+    fn error_snippet() {
+        let src = "html\n\tbody/\n\t\tp";
+        assert_eq!(
+            Error::new("".to_string(), 6, 11).snippet(src),
+            "2 | \tbody/\n  | \t^~~~~\n"
+        );
+        assert_eq!(
+            Error::new("".to_string(), 17, 30).snippet(src),
+            "3 | \t\tp\n  | \t\t ^\n"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use error::{Error, Result};
+use parse::Attrs;
+use pulldown_cmark;
+
+// Transforms the text of a `:name(opts)` block. Filters are shared by the
+// threads compiling templates in parallel.
+pub trait Filter: Send + Sync {
+    fn apply(&self, text: &str, opts: &Attrs) -> Result<String>;
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// `:plain` passes the text through as is
+pub struct Plain;
+
+impl Filter for Plain {
+    fn apply(&self, text: &str, _opts: &Attrs) -> Result<String> {
+        Ok(text.to_string())
+    }
+}
+
+// `:escape` escapes HTML special characters
+pub struct Escape;
+
+impl Filter for Escape {
+    fn apply(&self, text: &str, _opts: &Attrs) -> Result<String> {
+        Ok(escape_html(text))
+    }
+}
+
+// `:cdata` wraps the text in a CDATA section
+pub struct Cdata;
+
+impl Filter for Cdata {
+    fn apply(&self, text: &str, _opts: &Attrs) -> Result<String> {
+        if text.contains("]]>") {
+            return Err(Error::new(
+                "text of :cdata cannot contain `]]>`".to_string(),
+                0,
+                0,
+            ));
+        }
+        Ok(format!("<![CDATA[\n{}\n]]>", text))
+    }
+}
+
+// `:markdown` renders CommonMark, with `(tables strikethrough tasklists)`
+// options enabling the extensions
+pub struct Markdown;
+
+impl Filter for Markdown {
+    fn apply(&self, text: &str, opts: &Attrs) -> Result<String> {
+        let mut options = pulldown_cmark::Options::empty();
+        for (name, _) in opts {
+            match &name[..] {
+                "tables" => options.insert(pulldown_cmark::Options::ENABLE_TABLES),
+                "strikethrough" => options.insert(pulldown_cmark::Options::ENABLE_STRIKETHROUGH),
+                "tasklists" => options.insert(pulldown_cmark::Options::ENABLE_TASKLISTS),
+                "footnotes" => options.insert(pulldown_cmark::Options::ENABLE_FOOTNOTES),
+                name => {
+                    return Err(Error::new(
+                        format!("unknown option `{}` for :markdown", name),
+                        0,
+                        0,
+                    ))
+                }
+            }
+        }
+        let parser = pulldown_cmark::Parser::new_ext(text, options);
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, parser);
+        Ok(html.trim_end().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_filters() {
+        let opts = vec![];
+        assert_eq!(Plain.apply("<b>", &opts).unwrap(), "<b>");
+        assert_eq!(
+            Escape.apply(r#"<a href="x">&"#, &opts).unwrap(),
+            "&lt;a href=&quot;x&quot;&gt;&amp;"
+        );
+        assert_eq!(
+            Cdata.apply("x < y", &opts).unwrap(),
+            "<![CDATA[\nx < y\n]]>"
+        );
+        assert!(Cdata.apply("]]>", &opts).is_err());
+        assert_eq!(
+            Markdown.apply("# Title\n\n*hi*", &opts).unwrap(),
+            "<h1>Title</h1>\n<p><em>hi</em></p>"
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use error::Error;
+use lex::{Lexer, Token, TokenType};
+use parse::Parser;
+
+// Lines whose attributes would make them longer than this are written with
+// one attribute per line
+const MAX_WIDTH: usize = 80;
+
+// Write a template again in the canonical style: two spaces per level, at
+// most one blank line in a row, `tag#id.class(name="value")` headers and
+// `| text` for piped text. Text, comments and literal HTML are copied from
+// the source, so the template compiles to the same HTML apart from the
+// order of attributes. Templates with errors are not formatted.
+pub fn format(src: &str) -> Result<String, Vec<Error>> {
+    let mut lexer = Lexer::new(src.to_string());
+    lexer.tokenize();
+    let tokens = lexer.get_tokens();
+    let mut parser = Parser::new(lexer.get_tokens());
+    parser.parse();
+    let mut errors = lexer.get_errors().to_vec();
+    errors.extend_from_slice(parser.get_errors());
+    if !errors.is_empty() {
+        errors.sort_by_key(|e| e.get_span());
+        return Err(errors);
+    }
+    let mut formatter = Formatter {
+        src,
+        tokens: &tokens,
+        index: 0,
+        depth: 0,
+        out: String::with_capacity(src.len()),
+        line_open: false,
+        blank: false,
+    };
+    formatter.format();
+    Ok(formatter.out)
+}
+
+struct Formatter<'a> {
+    src: &'a str,
+    tokens: &'a [Token],
+    index: usize,
+    depth: usize,
+    out: String,
+    // Whether something has been written on the current line
+    line_open: bool,
+    // Whether a blank line comes before the next line
+    blank: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn format(&mut self) {
+        while let Some(token) = self.tokens.get(self.index) {
+            self.index += 1;
+            match token.get_type() {
+                TokenType::NewLine if self.line_open => {
+                    self.out.push('\n');
+                    self.line_open = false;
+                }
+                TokenType::NewLine => self.blank = true,
+                TokenType::Indent => self.depth += 1,
+                TokenType::Outdent => self.depth -= 1,
+                TokenType::Doctype(name) => {
+                    self.start_line();
+                    self.out.push_str(&format!("doctype {}\n", name));
+                    self.line_open = false;
+                }
+                ty => {
+                    self.start_line();
+                    self.format_inline(token, ty);
+                }
+            }
+        }
+        if self.line_open {
+            self.out.push('\n');
+        }
+    }
+
+    // Indent the line if nothing has been written on it yet
+    fn start_line(&mut self) {
+        if self.line_open {
+            return;
+        }
+        if self.blank && !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.blank = false;
+        self.line_open = true;
+        self.out.push_str(&indent(self.depth));
+    }
+
+    // Write a token that is not a line break or indentation
+    fn format_inline(&mut self, token: &Token, ty: &TokenType) {
+        let (start, end) = token.get_span();
+        match ty {
+            TokenType::Tag(_) | TokenType::Id(_) | TokenType::Class(_) => {
+                self.index -= 1;
+                self.format_header(false);
+            }
+            TokenType::Text(body) => self.format_text(body, start, end),
+            TokenType::Colon => self.out.push_str(": "),
+            TokenType::InterpolationStart => {
+                // The text before it is empty, leaving just `|` or a space
+                let before = self.src[..start].trim_end_matches(' ');
+                if before.ends_with('|') {
+                    self.out.push_str("| ");
+                } else if before.len() < start && !self.out.ends_with(char::is_whitespace) {
+                    self.out.push(' ');
+                }
+                self.out.push_str("#[");
+                while let Some(t) = self.tokens.get(self.index) {
+                    if let TokenType::Tag(_) | TokenType::Id(_) | TokenType::Class(_) = t.get_type()
+                    {
+                        self.format_header(true);
+                        continue;
+                    }
+                    self.index += 1;
+                    self.format_inline(t, t.get_type());
+                    if *t.get_type() == TokenType::InterpolationEnd {
+                        break;
+                    }
+                }
+            }
+            TokenType::InterpolationEnd => self.out.push(']'),
+            TokenType::Html(body) => {
+                let mut lines = body.split('\n');
+                self.out.push_str(lines.next().unwrap_or_default());
+                let depth = self.depth;
+                self.push_lines(lines, depth);
+            }
+            TokenType::Filter(name) => {
+                self.out.push(':');
+                self.out.push_str(name);
+                let mut opts = vec![];
+                while let Some(t) = self.tokens.get(self.index) {
+                    match t.get_type() {
+                        TokenType::Attr(name, value) => opts.push(attr(name, value)),
+                        _ => break,
+                    }
+                    self.index += 1;
+                }
+                if !opts.is_empty() {
+                    self.out.push_str(&format!("({})", opts.join(" ")));
+                }
+            }
+            TokenType::Include(path) => self.out.push_str(&format!("include {}", path)),
+            TokenType::Comment(body) | TokenType::UnbufferedComment(body) => {
+                self.out.push_str(match ty {
+                    TokenType::Comment(_) => "//",
+                    _ => "//-",
+                });
+                let mut lines = body.split('\n');
+                self.out.push_str(lines.next().unwrap_or_default());
+                let depth = self.depth + 1;
+                self.push_lines(lines, depth);
+            }
+            // Only found in headers, or not at all after a successful parse
+            TokenType::Attr(name, value) => self.out.push_str(&format!("({})", attr(name, value))),
+            _ => self.out.push_str(&self.src[start..end]),
+        }
+    }
+
+    // Write the tag, id, classes, attributes and markers starting at the
+    // current token. Attributes are split over lines when they make a
+    // line too long, except in interpolations.
+    fn format_header(&mut self, inline: bool) {
+        let mut name = None;
+        let (mut ids, mut classes, mut attrs) = (vec![], vec![], vec![]);
+        let (mut trim_inner, mut trim_outer, mut slash) = (false, false, false);
+        while let Some(t) = self.tokens.get(self.index) {
+            match t.get_type() {
+                TokenType::Tag(tag) if name.is_none() && ids.is_empty() && classes.is_empty() => {
+                    name = Some(tag.to_string())
+                }
+                TokenType::Id(id) => ids.push(id.to_string()),
+                TokenType::Class(class) => classes.push(class.to_string()),
+                TokenType::Attr(name, value) => attrs.push(attr(name, value)),
+                TokenType::TrimInner => trim_inner = true,
+                TokenType::TrimOuter => trim_outer = true,
+                TokenType::Slash => slash = true,
+                _ => break,
+            }
+            self.index += 1;
+        }
+
+        let mut header = match name {
+            // `div` is implied by an id or class
+            Some(ref name) if name == "div" && !(ids.is_empty() && classes.is_empty()) => {
+                "".to_string()
+            }
+            Some(name) => name,
+            None => "".to_string(),
+        };
+        for id in ids {
+            header.push('#');
+            header.push_str(&id);
+        }
+        for class in classes {
+            header.push('.');
+            header.push_str(&class);
+        }
+        let mut markers = "".to_string();
+        if trim_inner {
+            markers.push('<');
+        }
+        if trim_outer {
+            markers.push('>');
+        }
+        if slash {
+            markers.push('/');
+        }
+
+        let column = self.out.len() - self.out.rfind('\n').map_or(0, |i| i + 1);
+        let width = column + header.len() + attrs.join(" ").len() + markers.len() + 2;
+        if inline || attrs.len() == 1 || width <= MAX_WIDTH {
+            if !attrs.is_empty() {
+                header.push_str(&format!("({})", attrs.join(" ")));
+            }
+        } else {
+            header.push('(');
+            for attr in attrs {
+                header.push('\n');
+                header.push_str(&indent(self.depth + 1));
+                header.push_str(&attr);
+            }
+            header.push('\n');
+            header.push_str(&indent(self.depth));
+            header.push(')');
+        }
+        header.push_str(&markers);
+        self.out.push_str(&header);
+    }
+
+    // Inline text is copied from the source to keep escapes such as `\#[`,
+    // piped text gets a single space after `|`, and a text block its own
+    // lines after `.` or a filter
+    fn format_text(&mut self, body: &str, start: usize, end: usize) {
+        let source = &self.src[start..end];
+        if let Some(text) = source.strip_prefix('|') {
+            let text = text.strip_prefix(' ').unwrap_or(text);
+            self.out.push_str("| ");
+            self.out.push_str(text);
+        } else if self.src[..start].ends_with('\n') {
+            // The block of a filter has no `.`
+            if self.src[..start - 1].ends_with('.') {
+                self.out.push('.');
+            }
+            let depth = self.depth + 1;
+            self.push_lines(body.split('\n'), depth);
+        } else {
+            // The lexer leaves the `.` of `tag. text` out of the text
+            if self.src[..start].ends_with('.') {
+                self.out.push('.');
+            }
+            self.out.push_str(source);
+        }
+    }
+
+    // Write lines of a block on lines of their own, leaving blank lines empty
+    fn push_lines<'b, I: Iterator<Item = &'b str>>(&mut self, lines: I, depth: usize) {
+        for line in lines {
+            self.out.push('\n');
+            if !line.is_empty() {
+                self.out.push_str(&indent(depth));
+                self.out.push_str(line);
+            }
+        }
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+// `name="value"`, in single quotes if the value has a double quote, or just
+// `name` when the value is empty
+pub fn attr(name: &str, value: &str) -> String {
+    if value.is_empty() {
+        name.to_string()
+    } else if value.contains('"') {
+        format!("{}='{}'", name, value)
+    } else {
+        format!("{}=\"{}\"", name, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use Compiler;
+
+    #[test]
+    fn fmt_canonical() {
+        let src = "doctype\n\n\nul\n\tli.a#b(x=1, y='q\"'\n\t\t  z) one\n\n\n\tli|two\n\t\t| #[em  three]\n\t\t//- note\n\t\t\tmore\n\tp.\n\t\t  text\n\t\tblock\n\t:markdown\n\t\t*hi*\ndiv#long(href=\"https://example.com/a/long/path\" title=\"A long title\" target=\"_blank\")\n\n";
+        let expected = "doctype html\n\nul\n  li#b.a(x=\"1\" y='q\"' z) one\n\n  li| two\n    | #[em  three]\n    //- note\n      more\n  p.\n      text\n    block\n  :markdown\n    *hi*\n#long(\n  href=\"https://example.com/a/long/path\"\n  title=\"A long title\"\n  target=\"_blank\"\n)\n";
+        assert_eq!(format(src).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+        assert!(format("p(").is_err());
+    }
+
+    // Formatting changes the source but not the HTML it compiles to
+    #[test]
+    fn fmt_keeps_html() {
+        let mut corpus: Vec<String> = [
+            "ul\n  li one\n  div#main\n    p x",
+            "tag. txt",
+            "p| z",
+            "p(a=1). x\ndiv.b\n  | y",
+            "p: div.a x",
+            "p #[div.a(title=\"t\") x] y",
+            "tag.\n(b=1)p",
+            ".ypre\n(c=\"q w\")/tag.tag.",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        for entry in fs::read_dir("samples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "pug") {
+                corpus.push(fs::read_to_string(path).unwrap());
+            }
+        }
+        for src in &corpus {
+            let before = Compiler::new().compile_with_path(src, None);
+            let formatted = match format(src) {
+                Ok(formatted) => formatted,
+                Err(_) => {
+                    assert!(before.is_err(), "{:?}", src);
+                    continue;
+                }
+            };
+            let after = Compiler::new().compile_with_path(&formatted, None).unwrap();
+            assert_eq!(after.get_html(), before.unwrap().get_html(), "{:?}", src);
+        }
+    }
+}
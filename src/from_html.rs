@@ -0,0 +1,718 @@
+use error::Error;
+use fmt::attr;
+use parse::{HTMLElement, Node};
+use validate::is_block;
+
+// Convert an HTML document or fragment to pug. The HTML is parsed into the
+// nodes the pug parser makes, which are written with `#id.class` shorthand,
+// `tag: child` for a single child, `tag text #[em more]` for text mixed
+// with inline elements, `| text` beside other elements, and text blocks
+// for the content of `script` and `style`, and of `pre`, `code` and
+// `textarea` as it is. Other whitespace between elements is dropped.
+pub fn convert(src: &str) -> Result<String, Vec<Error>> {
+    let mut parser = HtmlParser {
+        src,
+        pos: 0,
+        open: vec![],
+        nodes: vec![],
+        errors: vec![],
+    };
+    parser.parse();
+    if !parser.errors.is_empty() {
+        return Err(parser.errors);
+    }
+    let mut pug = "".to_string();
+    for node in &parser.nodes {
+        write_node(node, 0, &mut pug);
+    }
+    Ok(pug)
+}
+
+// A forgiving HTML parser: end tags without a start tag are ignored and
+// elements left open are closed at the end, as browsers do. Only what pug
+// cannot express is an error.
+struct HtmlParser<'a> {
+    src: &'a str,
+    pos: usize,
+    // Elements not closed yet, innermost last
+    open: Vec<HTMLElement>,
+    nodes: Vec<Node>,
+    errors: Vec<Error>,
+}
+
+impl<'a> HtmlParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn error(&mut self, message: String, start: usize, end: usize) {
+        self.errors.push(Error::new(message, start, end));
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, cond: F) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !cond(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.take_while(|c| c.is_ascii_whitespace());
+    }
+
+    fn parse(&mut self) {
+        while self.pos < self.src.len() {
+            let rest = self.rest();
+            if rest.starts_with("<!--") {
+                self.parse_comment();
+            } else if rest.starts_with("<!") || rest.starts_with("<?") {
+                self.parse_declaration();
+            } else if rest.starts_with("</") {
+                self.parse_end_tag();
+            } else if rest.starts_with('<')
+                && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic())
+            {
+                self.parse_start_tag();
+            } else {
+                self.parse_text();
+            }
+        }
+        while !self.open.is_empty() {
+            self.close();
+        }
+    }
+
+    // The children of the innermost open element, or the top-level nodes
+    fn children(&mut self) -> &mut Vec<Node> {
+        match self.open.last_mut() {
+            Some(e) => e.get_children_mut(),
+            None => &mut self.nodes,
+        }
+    }
+
+    // Close the innermost open element
+    fn close(&mut self) {
+        let e = self.open.pop().unwrap();
+        self.children().push(Node::Element(Box::new(e)));
+    }
+
+    fn parse_comment(&mut self) {
+        let start = self.pos;
+        let body = start + 4;
+        match self.src[body..].find("-->") {
+            Some(len) => {
+                self.pos = body + len + 3;
+                let comment =
+                    Node::Comment(self.src[body..body + len].to_string(), (start, self.pos));
+                self.children().push(comment);
+            }
+            None => {
+                self.error("missing `-->` to close comment".to_string(), start, body);
+                self.pos = self.src.len();
+            }
+        }
+    }
+
+    // `<!DOCTYPE html>`. Other declarations and processing instructions are
+    // dropped.
+    fn parse_declaration(&mut self) {
+        let start = self.pos;
+        let end = match self.rest().find('>') {
+            Some(len) => start + len + 1,
+            None => {
+                self.error(
+                    "missing `>` to close declaration".to_string(),
+                    start,
+                    start + 2,
+                );
+                self.pos = self.src.len();
+                return;
+            }
+        };
+        self.pos = end;
+        let declaration = &self.src[start + 2..end - 1];
+        if declaration.len() >= 7 && declaration[..7].eq_ignore_ascii_case("doctype") {
+            let name = declaration[7..].split_whitespace().next().unwrap_or("html");
+            let doctype = Node::Doctype(name.to_ascii_lowercase(), (start, end));
+            self.children().push(doctype);
+        }
+    }
+
+    fn parse_start_tag(&mut self) {
+        let start = self.pos;
+        self.pos += 1;
+        let name = self
+            .take_while(|c| !c.is_ascii_whitespace() && c != '/' && c != '>')
+            .to_ascii_lowercase();
+        if !is_tag_name(&name) {
+            self.error(
+                format!("`<{}>` cannot be written in pug", name),
+                start,
+                self.pos,
+            );
+        }
+        let mut element = HTMLElement::new(name.to_string(), start, self.pos);
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.is_empty() {
+                let end = start + 1 + name.len();
+                self.error(format!("missing `>` to close `<{}`", name), start, end);
+                return;
+            } else if rest.starts_with('>') {
+                self.pos += 1;
+                break;
+            } else if rest.starts_with("/>") {
+                self.pos += 2;
+                if !element.is_void() {
+                    element.set_self_closing();
+                }
+                break;
+            } else if rest.starts_with(['/', '=']) {
+                self.pos += 1;
+                continue;
+            }
+            let attr_start = self.pos;
+            let attr_name = self.take_while(|c| !c.is_ascii_whitespace() && !"/>=".contains(c));
+            if !is_tag_name(attr_name) {
+                self.error(
+                    format!("attribute `{}` cannot be written in pug", attr_name),
+                    attr_start,
+                    self.pos,
+                );
+            }
+            self.skip_whitespace();
+            let value = match self.rest().starts_with('=') {
+                true => {
+                    self.pos += 1;
+                    self.skip_whitespace();
+                    self.parse_attr_value()
+                }
+                false => "",
+            };
+            element.push_attr(attr_name.to_string(), value.to_string());
+        }
+
+        self.close_implied(&name);
+        if element.is_void() || element.is_self_closing() {
+            self.children().push(Node::Element(Box::new(element)));
+            return;
+        }
+        if matches!(&name[..], "script" | "style" | "textarea" | "title") {
+            // Raw text up to the end tag
+            let close = format!("</{}", name);
+            let len = self.rest().to_ascii_lowercase().find(&close);
+            let text_start = self.pos;
+            self.pos += len.unwrap_or(self.rest().len());
+            if self.pos > text_start {
+                let text = self.src[text_start..self.pos].to_string();
+                element.push_child(Node::Text(text, (text_start, self.pos)));
+            }
+            self.pos += self.rest().find('>').map_or(self.rest().len(), |i| i + 1);
+            self.children().push(Node::Element(Box::new(element)));
+            return;
+        }
+        self.open.push(element);
+    }
+
+    fn parse_attr_value(&mut self) -> &'a str {
+        let quote = match self.rest().chars().next() {
+            Some(c) if c == '"' || c == '\'' => c,
+            _ => return self.take_while(|c| !c.is_ascii_whitespace() && c != '>'),
+        };
+        let start = self.pos;
+        self.pos += 1;
+        let value = self.take_while(|c| c != quote);
+        if self.rest().is_empty() {
+            self.error("missing closing quote".to_string(), start, start + 1);
+        } else {
+            self.pos += 1;
+        }
+        value
+    }
+
+    // Close the elements that a start tag `name` ends without an end tag,
+    // such as an open `p` before a `div`
+    fn close_implied(&mut self, name: &str) {
+        while let Some(open) = self.open.last() {
+            let implied = match open.get_name() {
+                "p" => is_block(name),
+                "li" => name == "li",
+                "dt" | "dd" => name == "dt" || name == "dd",
+                "option" => name == "option" || name == "optgroup",
+                "td" | "th" => matches!(name, "td" | "th" | "tr"),
+                "tr" => name == "tr",
+                _ => false,
+            };
+            if !implied {
+                break;
+            }
+            self.close();
+        }
+    }
+
+    // An end tag closes the elements opened inside its element, and is
+    // ignored when its element is not open
+    fn parse_end_tag(&mut self) {
+        let start = self.pos;
+        let end = match self.rest().find('>') {
+            Some(len) => start + len + 1,
+            None => {
+                self.error("missing `>` to close end tag".to_string(), start, start + 2);
+                self.pos = self.src.len();
+                return;
+            }
+        };
+        self.pos = end;
+        let name = self.src[start + 2..end - 1].trim().to_ascii_lowercase();
+        if let Some(i) = self.open.iter().rposition(|e| e.get_name() == name) {
+            while self.open.len() > i {
+                self.close();
+            }
+        }
+    }
+
+    fn parse_text(&mut self) {
+        let start = self.pos;
+        // A `<` that starts no tag is text
+        let rest = self.rest();
+        let first = rest.chars().next().unwrap().len_utf8();
+        self.pos += rest[first..].find('<').map_or(rest.len(), |i| i + first);
+        let (text, end) = (&self.src[start..self.pos], self.pos);
+        let children = self.children();
+        if let Some(Node::Text(body, span)) = children.last_mut() {
+            if span.1 == start {
+                body.push_str(text);
+                span.1 = end;
+                return;
+            }
+        }
+        children.push(Node::Text(text.to_string(), (start, end)));
+    }
+}
+
+fn write_node(node: &Node, depth: usize, pug: &mut String) {
+    match node {
+        Node::Doctype(name, _) => pug.push_str(&format!("doctype {}\n", name)),
+        Node::Element(e) => {
+            pug.push_str(&indent(depth));
+            write_element(e, depth, pug);
+        }
+        Node::Text(body, _) => {
+            for line in body.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                pug.push_str(&format!("{}| {}\n", indent(depth), escape(line)));
+            }
+        }
+        Node::Comment(body, _) => {
+            let mut lines = body.lines();
+            pug.push_str(&indent(depth));
+            pug.push_str("//");
+            pug.push_str(lines.next().unwrap_or_default().trim_end());
+            pug.push('\n');
+            write_block(&lines.collect::<Vec<&str>>(), depth + 1, pug);
+        }
+        _ => (),
+    }
+}
+
+// Write an element from its header on, its line already indented
+fn write_element(e: &HTMLElement, depth: usize, pug: &mut String) {
+    pug.push_str(&header(e));
+    if is_preformatted(e.get_name()) && !e.get_children().is_empty() {
+        pug.push_str(".\n");
+        write_preformatted(&inner_html(e.get_children()), depth + 1, pug);
+        return;
+    }
+    let children: Vec<&Node> = e
+        .get_children()
+        .iter()
+        .filter(|node| !matches!(node, Node::Text(body, _) if body.trim().is_empty()))
+        .collect();
+    if matches!(e.get_name(), "script" | "style") {
+        if let [Node::Text(body, _)] = children[..] {
+            pug.push_str(".\n");
+            write_block(&body.lines().collect::<Vec<&str>>(), depth + 1, pug);
+            return;
+        }
+    }
+    match children[..] {
+        [] => pug.push('\n'),
+        [Node::Element(child)] => {
+            pug.push_str(": ");
+            write_element(child, depth, pug);
+        }
+        [Node::Text(body, _)] if body.trim().lines().count() == 1 => {
+            pug.push(' ');
+            pug.push_str(&escape(body.trim()));
+            pug.push('\n');
+        }
+        // Text mixed with elements stays on one line, as `| text` after an
+        // element would not show where the whitespace between them is
+        _ if children.iter().all(|node| is_inline(node)) => {
+            pug.push(' ');
+            pug.push_str(inline(e.get_children()).trim());
+            pug.push('\n');
+        }
+        _ => {
+            pug.push('\n');
+            for child in children {
+                write_node(child, depth + 1, pug);
+            }
+        }
+    }
+}
+
+// Text, or an element that can be written as `#[tag text]`
+fn is_inline(node: &Node) -> bool {
+    match node {
+        Node::Text(body, _) => !body.contains(['[', ']']),
+        Node::Element(e) => {
+            is_phrasing(e.get_name())
+                && !matches!(e.get_name(), "pre" | "textarea")
+                && e.get_children().iter().all(is_inline)
+                && !(e.get_name() == "code" && inner_html(e.get_children()).contains('\n'))
+        }
+        _ => false,
+    }
+}
+
+// Nodes as text with `#[tag text]` interpolations. Whitespace is collapsed
+// as the browser would, except in `code`.
+fn inline(nodes: &[Node]) -> String {
+    let mut text = "".to_string();
+    for node in nodes {
+        match node {
+            Node::Text(body, _) => {
+                let mut collapsed = "".to_string();
+                let mut space = false;
+                for c in body.chars() {
+                    match c.is_ascii_whitespace() {
+                        true if space => (),
+                        true => collapsed.push(' '),
+                        false => collapsed.push(c),
+                    }
+                    space = c.is_ascii_whitespace();
+                }
+                text.push_str(&escape(&collapsed));
+            }
+            Node::Element(e) => {
+                text.push_str("#[");
+                text.push_str(&header(e));
+                let inner = match e.get_name() {
+                    "code" => inner_html(e.get_children()),
+                    _ => inline(e.get_children()).trim().to_string(),
+                };
+                if !inner.is_empty() {
+                    text.push(' ');
+                    text.push_str(&inner);
+                }
+                text.push(']');
+            }
+            _ => (),
+        }
+    }
+    text
+}
+
+// The content of `pre`, `code` and `textarea` is written as it is, HTML
+// included, in a text block
+fn is_preformatted(name: &str) -> bool {
+    matches!(name, "pre" | "code" | "textarea")
+}
+
+// Elements that the renderer keeps on the line of the text around them
+fn is_phrasing(name: &str) -> bool {
+    matches!(
+        name,
+        "a" | "abbr"
+            | "b"
+            | "bdi"
+            | "bdo"
+            | "br"
+            | "cite"
+            | "code"
+            | "data"
+            | "dfn"
+            | "em"
+            | "i"
+            | "img"
+            | "input"
+            | "kbd"
+            | "label"
+            | "mark"
+            | "q"
+            | "s"
+            | "samp"
+            | "small"
+            | "span"
+            | "strong"
+            | "sub"
+            | "sup"
+            | "time"
+            | "u"
+            | "var"
+            | "wbr"
+    )
+}
+
+// Nodes written back as HTML
+fn inner_html(nodes: &[Node]) -> String {
+    let mut html = "".to_string();
+    for node in nodes {
+        match node {
+            Node::Text(body, _) => html.push_str(body),
+            Node::Comment(body, _) => html.push_str(&format!("<!--{}-->", body)),
+            Node::Element(e) => {
+                html.push('<');
+                html.push_str(e.get_name());
+                for (name, value) in e.get_attrs() {
+                    match value.contains('"') {
+                        true => html.push_str(&format!(" {}='{}'", name, value)),
+                        false => html.push_str(&format!(" {}=\"{}\"", name, value)),
+                    }
+                }
+                if e.is_self_closing() {
+                    html.push_str("/>");
+                } else if e.is_void() {
+                    html.push('>');
+                } else {
+                    html.push('>');
+                    html.push_str(&inner_html(e.get_children()));
+                    html.push_str(&format!("</{}>", e.get_name()));
+                }
+            }
+            _ => (),
+        }
+    }
+    html
+}
+
+// Preformatted content in a text block. A text block loses the
+// indentation its lines share, whitespace-only lines and line breaks at its
+// end, so those are written as character references.
+fn write_preformatted(content: &str, depth: usize, pug: &mut String) {
+    let trimmed = content.trim_end_matches('\n');
+    let mut lines: Vec<String> = trimmed
+        .split('\n')
+        .map(|line| match line.trim().is_empty() {
+            true => line.replacen(' ', "&#32;", 1).replacen('\t', "&#9;", 1),
+            false => line.to_string(),
+        })
+        .collect();
+    let shared = lines
+        .iter()
+        .filter(|l| !l.is_empty())
+        .all(|l| l.starts_with([' ', '\t']));
+    if let Some(line) = lines.iter_mut().find(|l| !l.is_empty()) {
+        if shared {
+            *line = match line.starts_with(' ') {
+                true => line.replacen(' ', "&#32;", 1),
+                false => line.replacen('\t', "&#9;", 1),
+            };
+        }
+    }
+    if let Some(line) = lines.last_mut() {
+        line.push_str(&"&#10;".repeat(content.len() - trimmed.len()));
+    }
+    for line in lines {
+        if !line.is_empty() {
+            pug.push_str(&indent(depth));
+            pug.push_str(&line);
+        }
+        pug.push('\n');
+    }
+}
+
+// `tag#id.class(name="value")`, leaving out `div` when an id or class
+// shows it. The shorthand is rendered first, so it is only used for an id
+// that is the first attribute and the classes right after it, keeping the
+// attributes in order. Ids and classes that are not names stay attributes.
+fn header(e: &HTMLElement) -> String {
+    let (mut id, mut classes, mut attrs) = (None, vec![], vec![]);
+    let mut rest = e.get_attrs();
+    if let Some((name, value)) = rest.first() {
+        if name == "id" && is_name(value) {
+            id = Some(value);
+            rest = &rest[1..];
+        }
+    }
+    if let Some((name, value)) = rest.first() {
+        if name == "class" {
+            let names = value.split_whitespace();
+            classes.extend(names.clone().take_while(|class| is_name(class)));
+            let long: Vec<&str> = names.skip(classes.len()).collect();
+            if !long.is_empty() {
+                attrs.push(attr(name, &long.join(" ")));
+            }
+            rest = &rest[1..];
+        }
+    }
+    for (name, value) in rest {
+        attrs.push(attr(name, &attr_value(value)));
+    }
+    let mut header = match e.get_name() {
+        "div" if id.is_some() || !classes.is_empty() => "".to_string(),
+        name => name.to_string(),
+    };
+    if let Some(id) = id {
+        header.push('#');
+        header.push_str(id);
+    }
+    for class in classes {
+        header.push('.');
+        header.push_str(class);
+    }
+    if !attrs.is_empty() {
+        header.push_str(&format!("({})", attrs.join(" ")));
+    }
+    if e.is_self_closing() {
+        header.push('/');
+    }
+    header
+}
+
+// Lines of a text block or comment, without the indentation they share or
+// blank lines around them
+fn write_block(lines: &[&str], depth: usize, pug: &mut String) {
+    let first = lines.iter().position(|l| !l.trim().is_empty());
+    let last = lines.iter().rposition(|l| !l.trim().is_empty());
+    let lines = match (first, last) {
+        (Some(first), Some(last)) => &lines[first..=last],
+        _ => return,
+    };
+    let strip = lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    for line in lines {
+        if line.trim().is_empty() {
+            pug.push('\n');
+        } else {
+            pug.push_str(&format!("{}{}\n", indent(depth), line[strip..].trim_end()));
+        }
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+// Text that the pug lexer reads back as it is: `#[` would start an
+// interpolation and `\#` would lose its backslash
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&'#')) => {
+                chars.next();
+                escaped.push_str("\\\\#");
+            }
+            ('#', Some(&'[')) => escaped.push_str("\\#"),
+            (c, _) => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Pug attribute values end at a line break and cannot have both kinds of
+// quotes
+fn attr_value(value: &str) -> String {
+    let value = value
+        .lines()
+        .map(str::trim)
+        .collect::<Vec<&str>>()
+        .join(" ");
+    match value.contains('\'') {
+        true => value.replace('"', "&quot;"),
+        false => value,
+    }
+}
+
+// Names the pug lexer reads as an id or class
+fn is_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Names the pug lexer reads as a tag or attribute
+fn is_tag_name(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic()) && is_name(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::Compiler;
+
+    #[test]
+    fn from_html_converts() {
+        let html = "<!DOCTYPE html>\n<html lang=\"en\">\n<head><title>Hi</title>\n<style>\n  p { color: red }\n</style></head>\n<body>\n  <!-- nav -->\n  <div class=\"box md:wide\" id=\"main\">\n    <ul><li><a href=\"/\">Home</a><li>About #[1]\n    </ul>\n    <p>Hello <b>world</b>\n    <div class=\"x y\"></div>\n    <img src=\"a.png\" alt=\"\"><br/>\n    <svg><path d='M0 \"1\"'/></svg>\n  </div>\n</body>\n</html>\n";
+        let pug = "doctype html\nhtml(lang=\"en\")\n  head\n    title Hi\n    style.\n      p { color: red }\n  body\n    // nav\n    .box(class=\"md:wide\" id=\"main\")\n      ul\n        li: a(href=\"/\") Home\n        li About \\#[1]\n      p Hello #[b world]\n      .x.y\n      img(src=\"a.png\" alt)\n      br\n      svg: path(d='M0 \"1\"')/\n";
+        assert_eq!(convert(html).unwrap(), pug);
+        let compiled = Compiler::new().compile(pug).unwrap();
+        assert!(compiled.contains("<div class=\"box md:wide\" id=\"main\">"));
+
+        let errors: Vec<String> = convert("<a @click=\"go\">\n<p")
+            .err()
+            .unwrap()
+            .iter()
+            .map(|e| format!("{:?} {}", e.get_span(), e))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "(3, 9) attribute `@click` cannot be written in pug",
+                "(16, 18) missing `>` to close `<p`",
+            ]
+        );
+    }
+
+    // The HTML compiled from the pug is the input, apart from whitespace
+    // outside preformatted elements
+    #[test]
+    fn from_html_round_trips() {
+        let fixtures = [
+            "<p>See <a href=\"/\">here</a>, ok.</p>",
+            "<div id=\"a\" class=\"b\"><p class=\"c d:e f\" id=\"g\">Use <code>a  b</code> and <em>x <i>y</i></em> <br>z</p><ul><li><a href=\"/\">Home</a></li><li>About #[1]</li></ul></div>",
+            "<pre>  a\n    b <b>x</b>\n\n</pre>",
+            "<div><pre>\nk\n</pre><textarea>\n  q  \n   \n</textarea><p>Hello\n  <b>world</b>\n  again</p><code>x\n  y</code></div>",
+        ];
+        for html in fixtures {
+            let pug = convert(html).unwrap();
+            let compiled = Compiler::new().compile(&pug).unwrap();
+            assert_eq!(normalize(&compiled), normalize(html), "{}", pug);
+            for pre in ["<pre>", "<textarea>", "<code>"] {
+                if let Some(start) = html.find(pre) {
+                    let close = pre.replace('<', "</");
+                    let end = html[start..].find(&close).unwrap() + start;
+                    assert!(decode(&compiled).contains(&html[start..end]), "{}", pug);
+                }
+            }
+        }
+    }
+
+    // The references written for whitespace that a text block would lose
+    fn decode(html: &str) -> String {
+        html.replace("&#32;", " ")
+            .replace("&#9;", "\t")
+            .replace("&#10;", "\n")
+    }
+
+    // Whitespace runs as one space, and none next to a tag
+    fn normalize(html: &str) -> String {
+        let html = decode(html);
+        let words: Vec<&str> = html.split_whitespace().collect();
+        let mut normalized = "".to_string();
+        for word in words {
+            if !(normalized.is_empty() || normalized.ends_with('>') || word.starts_with('<')) {
+                normalized.push(' ');
+            }
+            normalized.push_str(word);
+        }
+        normalized
+    }
+}
@@ -1,12 +1,172 @@
+use encoding_rs::{DecoderResult, UTF_16BE, UTF_16LE, UTF_8};
 use std::fs::File;
 use std::io;
-use std::io::BufRead;
-use std::io::BufReader;
+use std::io::Read;
 
-pub fn read_file(filename: &str) -> String {
+pub use encoding_rs::Encoding;
+
+// Template source decoded to UTF-8 with line breaks normalised to LF
+pub struct Source {
+    text: String,
+    encoding: &'static Encoding,
+    // (offset in `text`, offset in the file) of every line start
+    lines: Vec<(usize, usize)>,
+}
+
+pub fn encoding_for_label(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.as_bytes())
+}
+
+pub fn read_file(filename: &str, encoding: Option<&'static Encoding>) -> io::Result<Source> {
     // file open
-    let file = File::open(filename).unwrap();
-    let reader = BufReader::new(file);
-    let lines: Vec<String> = reader.lines().collect::<io::Result<Vec<String>>>().unwrap();
-    lines.join("\n") // CRLF => LF
+    let mut bytes = vec![];
+    File::open(filename)?.read_to_end(&mut bytes)?;
+    Source::from_bytes(&bytes, encoding)
+}
+
+pub fn read_stdin(encoding: Option<&'static Encoding>) -> io::Result<Source> {
+    let mut bytes = vec![];
+    io::stdin().read_to_end(&mut bytes)?;
+    Source::from_bytes(&bytes, encoding)
+}
+
+impl Source {
+    // Decode `bytes` as UTF-8, or as `encoding` when given. A byte order mark
+    // takes precedence over both.
+    pub fn from_bytes(bytes: &[u8], encoding: Option<&'static Encoding>) -> io::Result<Source> {
+        let (encoding, bom) = match Encoding::for_bom(bytes) {
+            Some((encoding, bom)) => (encoding, bom),
+            None => (encoding.unwrap_or(UTF_8), 0),
+        };
+        let body = &bytes[bom..];
+        let decoded = match encoding.decode_without_bom_handling_and_without_replacement(body) {
+            Some(decoded) => decoded,
+            None => return Err(decode_error(body, bom, encoding)),
+        };
+        let mut source = Source {
+            text: String::with_capacity(decoded.len()),
+            encoding,
+            lines: vec![(0, bom)],
+        };
+        let mut original = bom;
+        let mut chars = decoded.chars().peekable();
+        while let Some(c) = chars.next() {
+            original += source.encoded_len(c);
+            if c == '\r' {
+                // CRLF => LF, CR => LF
+                if chars.peek() == Some(&'\n') {
+                    chars.next();
+                    original += source.encoded_len('\n');
+                }
+                source.text.push('\n');
+            } else {
+                source.text.push(c);
+            }
+            if c == '\r' || c == '\n' {
+                source.lines.push((source.text.len(), original));
+            }
+        }
+        Ok(source)
+    }
+
+    pub fn get_text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn get_encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    // Byte offset in the original file of a byte offset in the decoded text
+    pub fn original_offset(&self, offset: usize) -> usize {
+        let line = match self
+            .lines
+            .binary_search_by(|&(start, _)| start.cmp(&offset))
+        {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let (start, original) = self.lines[line];
+        let end = offset.min(self.text.len());
+        original
+            + self.text[start..end]
+                .chars()
+                .map(|c| self.encoded_len(c))
+                .sum::<usize>()
+    }
+
+    fn encoded_len(&self, c: char) -> usize {
+        if self.encoding == UTF_8 {
+            c.len_utf8()
+        } else if self.encoding == UTF_16LE || self.encoding == UTF_16BE {
+            c.len_utf16() * 2
+        } else {
+            let mut buf = [0; 4];
+            self.encoding.encode(c.encode_utf8(&mut buf)).0.len()
+        }
+    }
+}
+
+// Describe where decoding failed, by line and byte offset in the file.
+// `bytes` follow a byte order mark of `bom` bytes.
+fn decode_error(bytes: &[u8], bom: usize, encoding: &'static Encoding) -> io::Error {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut buf = String::with_capacity(decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(0));
+    let offset = match decoder.decode_to_string_without_replacement(bytes, &mut buf, true) {
+        (DecoderResult::Malformed(bad, extra), read) => read - bad as usize - extra as usize,
+        (_, read) => read,
+    };
+    let line = bytes[..offset].iter().filter(|&&b| b == b'\n').count() + 1;
+    let mut message = format!(
+        "invalid {} at line {} (byte {})",
+        encoding.name(),
+        line,
+        bom + offset
+    );
+    if encoding == UTF_8 {
+        message.push_str("; use --encoding to read other encodings such as Shift_JIS");
+    }
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::SHIFT_JIS;
+
+    #[test]
+    fn source_normalises_line_breaks() {
+        let bytes = b"\xEF\xBB\xBFhtml\r\n  body\r  p\n";
+        let source = Source::from_bytes(bytes, None).unwrap();
+        assert_eq!(source.get_text(), "html\n  body\n  p\n");
+        assert_eq!(source.original_offset(0), 3);
+        assert_eq!(source.original_offset(7), 11);
+        assert_eq!(source.original_offset(15), 19);
+
+        let error = Source::from_bytes(b"\xEF\xBB\xBFp\n\xFF", None)
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("invalid UTF-8 at line 2 (byte 5)"));
+    }
+
+    #[test]
+    fn source_decodes_utf16_with_bom() {
+        let bytes = b"\xFF\xFEp\x00 \x00\x42\x30\r\x00\n\x00";
+        let source = Source::from_bytes(bytes, None).unwrap();
+        assert_eq!(source.get_text(), "p \u{3042}\n");
+        assert_eq!(source.original_offset(5), 8);
+    }
+
+    #[test]
+    fn source_decodes_legacy_encoding() {
+        // "p ページ\r\np" in Shift_JIS
+        let bytes = b"p \x83\x79\x81\x5B\x83\x57\r\np";
+        assert!(Source::from_bytes(bytes, None).is_err());
+        let source = Source::from_bytes(bytes, Some(SHIFT_JIS)).unwrap();
+        assert_eq!(source.get_text(), "p ページ\np");
+        let p = source.get_text().rfind('p').unwrap();
+        assert_eq!(source.original_offset(p), 10);
+    }
 }
@@ -0,0 +1,162 @@
+use json;
+use parse::Node;
+use render;
+use render::Chunk;
+
+// A JavaScript module exporting `template(locals)`, which returns the HTML of
+// a compiled template like a function from `pug.compileClient`, so that
+// browsers can render it without the pug compiler. The nodes are rendered
+// into string literals, one for each run of markup and text between
+// attribute values, which are escaped as they are appended. Templates have
+// no expressions yet, so `locals` is not read.
+pub fn generate(nodes: &[Node]) -> String {
+    let chunks = render::render_chunks(nodes);
+    let mut js = "// Generated by pugrs, do not edit\n".to_string();
+    if chunks.iter().any(|chunk| matches!(chunk, Chunk::Attr(_))) {
+        js.push_str(ESCAPE);
+    }
+    js.push_str("export function template(locals) {\n");
+    js.push_str("  var pug_html = \"\";\n");
+    for chunk in &chunks {
+        match chunk {
+            Chunk::Html(html) => js.push_str(&format!("  pug_html += {};\n", string(html))),
+            Chunk::Attr(value) => {
+                js.push_str(&format!("  pug_html += pug_escape({});\n", string(value)))
+            }
+        }
+    }
+    js.push_str("  return pug_html;\n}\n\nexport default template;\n");
+    js
+}
+
+// Escapes an attribute value as `render::escape_attr` does
+const ESCAPE: &str = r#"function pug_escape(value) {
+  return String(value).replace(/"/g, "&quot;");
+}
+
+"#;
+
+// A JavaScript string literal. JSON escapes are valid in JavaScript, but
+// line and paragraph separators end a string in older engines.
+fn string(s: &str) -> String {
+    json::string(s)
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use Compiler;
+
+    #[test]
+    fn js_writes_module() {
+        let output = Compiler::new()
+            .compile_with_path("p(title='a \"b\"') say \"a\\b\"\n  | x\u{2028}y", None)
+            .unwrap();
+        assert_eq!(
+            generate(output.get_nodes()),
+            r#"// Generated by pugrs, do not edit
+function pug_escape(value) {
+  return String(value).replace(/"/g, "&quot;");
+}
+
+export function template(locals) {
+  var pug_html = "";
+  pug_html += "<p title=\"";
+  pug_html += pug_escape("a \"b\"");
+  pug_html += "\">\n  say \"a\\b\"\n  x\u2028y\n</p>\n";
+  return pug_html;
+}
+
+export default template;
+"#
+        );
+    }
+
+    // What the module returns: its string literals appended in order, the
+    // ones passed to `pug_escape` with `"` replaced
+    fn evaluate(js: &str) -> String {
+        let mut html = "".to_string();
+        for line in js.lines() {
+            let value = match line.trim().strip_prefix("pug_html += ") {
+                Some(value) => value.strip_suffix(';').unwrap(),
+                None => continue,
+            };
+            match value.strip_prefix("pug_escape(") {
+                Some(arg) => {
+                    let arg = unquote(arg.strip_suffix(')').unwrap());
+                    html.push_str(&arg.replace('"', "&quot;"));
+                }
+                None => html.push_str(&unquote(value)),
+            }
+        }
+        html
+    }
+
+    // The value of a string literal written by `string`
+    fn unquote(literal: &str) -> String {
+        let mut chars = literal.strip_prefix('"').unwrap().chars();
+        let mut value = "".to_string();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next().unwrap() {
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'u' => {
+                        let code: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&code, 16).unwrap();
+                        value.push(char::from_u32(code).unwrap());
+                    }
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+        value
+    }
+
+    // The module returns the HTML of the compiler for each sample, checked
+    // with node too when it is installed
+    #[test]
+    fn js_renders_samples() {
+        let mut corpus = vec!["p(title='a \"b\"') x\\y\r\n  | \u{1}\t\u{2028}\u{2029}".to_string()];
+        for entry in fs::read_dir("samples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "pug") {
+                corpus.push(fs::read_to_string(path).unwrap());
+            }
+        }
+        let dir = env::temp_dir().join(format!("pugrs-js-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (i, src) in corpus.iter().enumerate() {
+            let output = Compiler::new().compile_with_path(src, None).unwrap();
+            let js = generate(output.get_nodes());
+            assert_eq!(evaluate(&js), output.get_html(), "{:?}", src);
+
+            let module = dir.join(format!("template{}.mjs", i));
+            fs::write(&module, &js).unwrap();
+            let script = format!(
+                "import t from {}; process.stdout.write(t({{}}));",
+                string(&module.to_string_lossy())
+            );
+            let node = process::Command::new("node")
+                .args(["--input-type=module", "-e", &script])
+                .output();
+            if let Ok(run) = node {
+                assert!(
+                    run.status.success(),
+                    "{}",
+                    String::from_utf8_lossy(&run.stderr)
+                );
+                assert_eq!(String::from_utf8(run.stdout).unwrap(), output.get_html());
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
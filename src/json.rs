@@ -0,0 +1,174 @@
+use lex::{Token, TokenType};
+use parse::Node;
+
+// Serialise tokens and nodes for `--dump`. Every object has a "type", and a
+// "span" of byte offsets in the source when it has one. Fields are written
+// in a fixed order so that dumps can be compared as text.
+
+pub fn tokens_to_json(tokens: &[Token]) -> String {
+    let items: Vec<String> = tokens.iter().map(token_to_json).collect();
+    format!("[{}]", items.join(","))
+}
+
+pub fn nodes_to_json(nodes: &[Node]) -> String {
+    let items: Vec<String> = nodes.iter().map(node_to_json).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn token_to_json(token: &Token) -> String {
+    let (name, value) = match token.get_type() {
+        TokenType::Doctype(name) => ("Doctype", Some(name)),
+        TokenType::NewLine => ("NewLine", None),
+        TokenType::Tag(name) => ("Tag", Some(name)),
+        TokenType::Id(name) => ("Id", Some(name)),
+        TokenType::Class(name) => ("Class", Some(name)),
+        TokenType::Attr(name, value) => {
+            return format!(
+                r#"{{"type":"Attr","name":{},"value":{},"span":{}}}"#,
+                string(name),
+                string(value),
+                span(token.get_span())
+            );
+        }
+        TokenType::Text(body) => ("Text", Some(body)),
+        TokenType::Colon => ("Colon", None),
+        TokenType::Indent => ("Indent", None),
+        TokenType::Outdent => ("Outdent", None),
+        TokenType::Slash => ("Slash", None),
+        TokenType::InterpolationStart => ("InterpolationStart", None),
+        TokenType::InterpolationEnd => ("InterpolationEnd", None),
+        TokenType::TrimInner => ("TrimInner", None),
+        TokenType::TrimOuter => ("TrimOuter", None),
+        TokenType::Html(body) => ("Html", Some(body)),
+        TokenType::Filter(name) => ("Filter", Some(name)),
+        TokenType::Include(path) => ("Include", Some(path)),
+        TokenType::Comment(body) => ("Comment", Some(body)),
+        TokenType::UnbufferedComment(body) => ("UnbufferedComment", Some(body)),
+    };
+    let value = match value {
+        Some(value) => format!(r#","value":{}"#, string(value)),
+        None => "".to_string(),
+    };
+    format!(
+        r#"{{"type":"{}"{},"span":{}}}"#,
+        name,
+        value,
+        span(token.get_span())
+    )
+}
+
+fn node_to_json(node: &Node) -> String {
+    let mut fields = vec![];
+    let name = match node {
+        Node::Empty => "Empty",
+        Node::Doctype(name, _) => {
+            fields.push(("value", string(name)));
+            "Doctype"
+        }
+        Node::Element(e) => {
+            fields.push(("name", string(e.get_name())));
+            fields.push(("attrs", attrs(e.get_attrs())));
+            fields.push(("self_closing", e.is_self_closing().to_string()));
+            fields.push(("trim_inner", e.trims_inner().to_string()));
+            fields.push(("trim_outer", e.trims_outer().to_string()));
+            "Element"
+        }
+        Node::Text(body, _) => {
+            fields.push(("value", string(body)));
+            "Text"
+        }
+        Node::Inline(_) => "Inline",
+        Node::Raw(body, _) => {
+            fields.push(("value", string(body)));
+            "Raw"
+        }
+        Node::Filter(block) => {
+            let filters: Vec<String> = block
+                .get_filters()
+                .iter()
+                .map(|(name, opts)| {
+                    format!(r#"{{"name":{},"opts":{}}}"#, string(name), attrs(opts))
+                })
+                .collect();
+            fields.push(("filters", format!("[{}]", filters.join(","))));
+            fields.push(("value", string(block.get_text())));
+            "Filter"
+        }
+        Node::Include(include) => {
+            fields.push(("value", string(include.get_path())));
+            "Include"
+        }
+        Node::Comment(body, _) => {
+            fields.push(("value", string(body)));
+            "Comment"
+        }
+    };
+    if let Some(s) = node.get_span() {
+        fields.push(("span", span(s)));
+    }
+    match node {
+        Node::Element(e) => fields.push(("children", nodes_to_json(e.get_children()))),
+        Node::Inline(nodes) => fields.push(("children", nodes_to_json(nodes))),
+        _ => (),
+    }
+    let mut json = format!(r#"{{"type":"{}""#, name);
+    for (key, value) in fields {
+        json.push_str(&format!(r#","{}":{}"#, key, value));
+    }
+    json.push('}');
+    json
+}
+
+fn attrs(attrs: &[(String, String)]) -> String {
+    let pairs: Vec<String> = attrs
+        .iter()
+        .map(|(name, value)| format!("[{},{}]", string(name), string(value)))
+        .collect();
+    format!("[{}]", pairs.join(","))
+}
+
+fn span((start, end): (usize, usize)) -> String {
+    format!("[{},{}]", start, end)
+}
+
+// A JSON string literal
+pub fn string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+    use parse::Parser;
+
+    #[test]
+    fn json_dump() {
+        let mut lexer = Lexer::new("a(href=\"#\") say \"hi\"\n  br".to_string());
+        lexer.tokenize();
+        let tokens = lexer.get_tokens();
+        assert_eq!(
+            tokens_to_json(&tokens[..3]),
+            r##"[{"type":"Tag","value":"a","span":[0,1]},{"type":"Attr","name":"href","value":"#","span":[2,10]},{"type":"Text","value":"say \"hi\"","span":[11,20]}]"##
+        );
+        let nodes = Parser::new(tokens).parse();
+        assert_eq!(
+            nodes_to_json(&nodes),
+            r##"[{"type":"Element","name":"a","attrs":[["href","#"]],"self_closing":false,"trim_inner":false,"trim_outer":false,"span":[0,10],"children":[{"type":"Text","value":"say \"hi\"","span":[11,20]},{"type":"Element","name":"br","attrs":[],"self_closing":false,"trim_inner":false,"trim_outer":false,"span":[23,25],"children":[]}]}]"##
+        );
+    }
+}
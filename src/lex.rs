@@ -1,5 +1,6 @@
-use std::fmt;
+use error::Error;
 use log::debug;
+use std::fmt;
 
 #[derive(Clone, PartialEq)]
 pub enum TokenType {
@@ -14,6 +15,17 @@ pub enum TokenType {
     Indent,
     Outdent,
     Slash,
+    InterpolationStart,
+    InterpolationEnd,
+    TrimInner,
+    TrimOuter,
+    Html(String),
+    Filter(String),
+    Include(String),
+    // `// text`, rendered as an HTML comment
+    Comment(String),
+    // `//- text`, left out of the HTML
+    UnbufferedComment(String),
 }
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -29,6 +41,42 @@ impl fmt::Display for TokenType {
             TokenType::Indent => write!(f, "Indent"),
             TokenType::Outdent => write!(f, "Outdent"),
             TokenType::Slash => write!(f, "Slash"),
+            TokenType::InterpolationStart => write!(f, "InterpolationStart"),
+            TokenType::InterpolationEnd => write!(f, "InterpolationEnd"),
+            TokenType::TrimInner => write!(f, "TrimInner"),
+            TokenType::TrimOuter => write!(f, "TrimOuter"),
+            TokenType::Html(body) => write!(f, "Html({})", body),
+            TokenType::Filter(name) => write!(f, "Filter({})", name),
+            TokenType::Include(path) => write!(f, "Include({})", path),
+            TokenType::Comment(body) => write!(f, "Comment({})", body),
+            TokenType::UnbufferedComment(body) => write!(f, "UnbufferedComment({})", body),
+        }
+    }
+}
+
+impl TokenType {
+    // How the token is called in error messages
+    pub fn describe(&self) -> &'static str {
+        match self {
+            TokenType::Doctype(_) => "doctype",
+            TokenType::NewLine => "line break",
+            TokenType::Tag(_) => "tag",
+            TokenType::Id(_) => "id",
+            TokenType::Class(_) => "class",
+            TokenType::Attr(_, _) => "attribute",
+            TokenType::Text(_) => "text",
+            TokenType::Colon => "`:`",
+            TokenType::Indent => "indentation",
+            TokenType::Outdent => "unindent",
+            TokenType::Slash => "`/`",
+            TokenType::InterpolationStart => "`#[`",
+            TokenType::InterpolationEnd => "`]`",
+            TokenType::TrimInner => "`<`",
+            TokenType::TrimOuter => "`>`",
+            TokenType::Html(_) => "HTML",
+            TokenType::Filter(_) => "filter",
+            TokenType::Include(_) => "include",
+            TokenType::Comment(_) | TokenType::UnbufferedComment(_) => "comment",
         }
     }
 }
@@ -43,6 +91,9 @@ impl Token {
     pub fn get_type(&self) -> &TokenType {
         &self.ty
     }
+    pub fn get_span(&self) -> (usize, usize) {
+        (self.start, self.end)
+    }
 }
 impl fmt::Debug for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -54,20 +105,55 @@ pub struct Lexer {
     src: String,
     tokens: Vec<Token>,
     pos: usize,
+    // Indentation of the line being lexed
+    line_indent: usize,
+    // Either ' ' or '\t', decided by the first indented line
+    indent_char: Option<char>,
+    errors: Vec<Error>,
 }
 
 use std::boxed::Box;
 use std::iter::Peekable;
 use std::str::Chars;
 
-type CharCond = Fn(char) -> bool;
+type CharCond = dyn Fn(char) -> bool;
+
+fn is_indent(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+// Join lines, removing the indentation they have in common. Blank lines
+// are kept empty.
+fn strip_indentation(lines: &[String]) -> String {
+    let strip = lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.chars().take_while(|&c| is_indent(c)).count())
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|l| if l.trim().is_empty() { "" } else { &l[strip..] })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+fn indent_name(c: char) -> &'static str {
+    match c {
+        '\t' => "tabs",
+        _ => "spaces",
+    }
+}
 
 impl Lexer {
     pub fn new(src: String) -> Lexer {
         Lexer {
-            src: src,
+            src,
             tokens: vec![],
             pos: 0,
+            line_indent: 0,
+            indent_char: None,
+            errors: vec![],
         }
     }
 
@@ -83,17 +169,36 @@ impl Lexer {
     pub fn get_tokens(&self) -> Vec<Token> {
         self.tokens.clone()
     }
+    pub fn get_errors(&self) -> &[Error] {
+        &self.errors
+    }
+    fn error(&mut self, message: String, start: usize, end: usize) {
+        self.errors.push(Error::new(message, start, end));
+    }
+    // Report an unexpected char and skip the rest of the line, so that
+    // lexing resumes on the next line
+    fn unexpected(&mut self, p: &mut Peekable<Chars>, context: &str) {
+        let start = self.pos;
+        let c = self.consume_next(p);
+        self.error(
+            format!("unexpected character `{}`{}", c, context),
+            start,
+            self.pos,
+        );
+        self.consume_while(p, Box::new(|c: char| -> bool { c != '\n' }));
+    }
     fn add_token(&mut self, ty: TokenType, start: usize, length: usize) {
         debug!("<{}: {}..{}>", &ty, &start, &length);
         self.tokens.push(Token {
-            ty: ty,
-            start: start,
+            ty,
+            start,
             end: start + length,
         });
     }
     fn consume_next(&mut self, p: &mut Peekable<Chars>) -> char {
-        self.pos += 1;
-        p.next().unwrap()
+        let c = p.next().unwrap();
+        self.pos += c.len_utf8();
+        c
     }
     fn consume_while(&mut self, p: &mut Peekable<Chars>, cb: Box<CharCond>) -> Option<String> {
         let mut v: Vec<char> = vec![];
@@ -103,7 +208,7 @@ impl Lexer {
         } {
             v.push(self.consume_next(p));
         }
-        if v.len() > 0 {
+        if !v.is_empty() {
             Some(v.iter().collect::<String>())
         } else {
             None
@@ -136,25 +241,437 @@ impl Lexer {
                 return None;
             }
         };
+        let start = self.pos - 1;
         let mut v: Vec<char> = vec![];
-        while p.peek().is_some() && *p.peek().unwrap() != sign {
+        while p.peek().is_some() && *p.peek().unwrap() != sign && *p.peek().unwrap() != '\n' {
             v.push(self.consume_next(p));
         }
-        self.consume_next(p);
+        if p.peek() == Some(&sign) {
+            self.consume_next(p);
+        } else {
+            self.error("missing closing quote".to_string(), start, self.pos);
+        }
         Some(v.iter().collect::<String>())
     }
+    fn lex_tag(&mut self, p: &mut Peekable<Chars>) {
+        let start = self.pos;
+        let name = self.consume_name(p).unwrap();
+        let len = name.len();
+        self.add_token(TokenType::Tag(name), start, len);
+    }
+    fn lex_id(&mut self, p: &mut Peekable<Chars>) {
+        let start = self.pos;
+        self.consume_next(p);
+        if let Some(name) = self.consume_name(p) {
+            let len = self.pos - start;
+            self.add_token(TokenType::Id(name), start, len);
+        };
+    }
+    fn lex_class(&mut self, p: &mut Peekable<Chars>) {
+        let start = self.pos;
+        self.consume_next(p);
+        if let Some(name) = self.consume_name(p) {
+            let len = self.pos - start;
+            self.add_token(TokenType::Class(name), start, len);
+        } else if p.peek().is_none() || p.peek() == Some(&'\n') {
+            // Found text block ("tag." followed by indented lines)
+            self.lex_text_block(p);
+        }
+    }
+    // Consume the lines indented deeper than the current line, leaving the
+    // line break before the next line. Trailing blank lines are not consumed.
+    fn consume_indented_lines(&mut self, p: &mut Peekable<Chars>) -> Vec<String> {
+        let mut lookahead = p.clone();
+        let mut lines: Vec<String> = vec![];
+        // Lines and chars up to the end of the last non-blank line
+        let mut kept = 0;
+        let mut length = 0;
+        let mut scanned = 0;
+        while lookahead.next() == Some('\n') {
+            scanned += 1;
+            let line: String = lookahead.clone().take_while(|&c| c != '\n').collect();
+            let count = line.chars().count();
+            let indent = line.chars().take_while(|&c| is_indent(c)).count();
+            if !line.trim().is_empty() {
+                if indent <= self.line_indent {
+                    break;
+                }
+                kept = lines.len() + 1;
+                length = scanned + count;
+            }
+            lines.push(line);
+            scanned += count;
+            for _ in 0..count {
+                lookahead.next();
+            }
+        }
+        lines.truncate(kept);
+        for _ in 0..length {
+            self.consume_next(p);
+        }
+        lines
+    }
+    // Lex the lines indented deeper than the current line as a single text
+    // token, keeping line breaks and indentation relative to the block.
+    fn lex_text_block(&mut self, p: &mut Peekable<Chars>) {
+        let start = self.pos + 1;
+        let lines = self.consume_indented_lines(p);
+        if lines.is_empty() {
+            return;
+        }
+        let body = strip_indentation(&lines);
+        let len = self.pos - start;
+        self.add_token(TokenType::Text(body), start, len);
+    }
+    // Lex `// comment` or `//- comment` with the lines nested under it
+    fn lex_comment(&mut self, p: &mut Peekable<Chars>) {
+        let start = self.pos;
+        self.consume_next(p);
+        self.consume_next(p);
+        let buffered = p.peek() != Some(&'-');
+        if !buffered {
+            self.consume_next(p);
+        }
+        let mut body = self
+            .consume_while(p, Box::new(|c: char| -> bool { c != '\n' }))
+            .unwrap_or_default();
+        let lines = self.consume_indented_lines(p);
+        if !lines.is_empty() {
+            body.push('\n');
+            body.push_str(&strip_indentation(&lines));
+        }
+        let len = self.pos - start;
+        let ty = match buffered {
+            true => TokenType::Comment(body),
+            false => TokenType::UnbufferedComment(body),
+        };
+        self.add_token(ty, start, len);
+    }
+    // Lex a line of literal HTML together with the lines nested under it
+    fn lex_html(&mut self, p: &mut Peekable<Chars>) {
+        let start = self.pos;
+        let mut body = self
+            .consume_while(p, Box::new(|c: char| -> bool { c != '\n' }))
+            .unwrap();
+        let indent = self.line_indent;
+        for line in self.consume_indented_lines(p) {
+            body.push('\n');
+            if !line.trim().is_empty() {
+                body.push_str(&line[indent..]);
+            }
+        }
+        let len = self.pos - start;
+        self.add_token(TokenType::Html(body), start, len);
+    }
+    // Attributes may span several lines. When the closing parenthesis is
+    // missing, the line is lexed again from `(` and skipped, and false is
+    // returned.
+    fn lex_attrs(&mut self, p: &mut Peekable<Chars>) -> bool {
+        let open = self.pos;
+        let (rewind, tokens) = (p.clone(), self.tokens.len());
+        self.consume_next(p);
+        loop {
+            if p.peek().is_none() {
+                *p = rewind;
+                self.pos = open;
+                self.tokens.truncate(tokens);
+                self.consume_while(p, Box::new(|c: char| -> bool { c != '\n' }));
+                // Keep the errors found on the line, such as a missing quote
+                let line_end = self.pos;
+                self.errors.retain(|e| e.get_span().0 < line_end);
+                self.error(
+                    "missing `)` to close attributes".to_string(),
+                    open,
+                    open + 1,
+                );
+                return false;
+            }
+            match *p.peek().unwrap() {
+                ')' => {
+                    self.consume_next(p);
+                    break;
+                }
+                c if c.is_ascii_whitespace() || c == ',' => {
+                    self.consume_next(p);
+                    continue;
+                }
+                c if c.is_ascii_alphabetic() => {
+                    // Found an attribute
+                    let start = self.pos;
+                    let name = self.consume_name(p).unwrap();
+                    let value: String = match p.peek() {
+                        Some(&c) if c.is_ascii_whitespace() => {
+                            self.consume_whitespaces(p);
+                            "".to_string()
+                        }
+                        Some(&'=') => {
+                            self.consume_next(p);
+                            match p.peek() {
+                                Some(&c) if c.is_ascii_whitespace() => {
+                                    self.consume_whitespaces(p);
+                                    "".to_string()
+                                }
+                                Some(&'"') | Some(&'\'') => self.consume_quoted(p).unwrap(),
+                                Some(&_) => self
+                                    .consume_while(
+                                        p,
+                                        Box::new(|c: char| -> bool {
+                                            !c.is_ascii_whitespace() && c != ')' && c != ','
+                                        }),
+                                    )
+                                    .unwrap_or_default(),
+                                None => "".to_string(),
+                            }
+                        }
+                        _ => "".to_string(),
+                    };
+                    let len = self.pos - start;
+                    self.add_token(TokenType::Attr(name, value), start, len);
+                    continue;
+                }
+                c => {
+                    let start = self.pos;
+                    self.consume_next(p);
+                    self.error(
+                        format!("unexpected character `{}` in attributes", c),
+                        start,
+                        self.pos,
+                    );
+                }
+            }
+        }
+        true
+    }
+    // Lex a run of text up to the end of line, splitting out `#[...]` tag
+    // interpolations. Inside an interpolation the run also ends at the
+    // closing `]`. `start` is where the token begins, which may be before
+    // `self.pos` when a leading `|` or space has already been consumed.
+    fn lex_text(&mut self, p: &mut Peekable<Chars>, start: usize, nested: bool) {
+        let mut start = start;
+        let mut body = String::new();
+        loop {
+            match p.peek() {
+                None | Some(&'\n') => break,
+                Some(&']') if nested => break,
+                Some(&'\\') => {
+                    // "\#[" is a literal "#["
+                    self.consume_next(p);
+                    if p.peek() == Some(&'#') {
+                        body.push(self.consume_next(p));
+                    } else {
+                        body.push('\\');
+                    }
+                }
+                Some(&'#') => {
+                    self.consume_next(p);
+                    if p.peek() != Some(&'[') {
+                        body.push('#');
+                        continue;
+                    }
+                    let interp = self.pos - 1;
+                    if !body.is_empty() {
+                        let len = interp - start;
+                        self.add_token(TokenType::Text(body), start, len);
+                        body = String::new();
+                    }
+                    self.consume_next(p);
+                    self.add_token(TokenType::InterpolationStart, interp, 2);
+                    self.lex_interpolation(p, interp);
+                    start = self.pos;
+                }
+                Some(_) => body.push(self.consume_next(p)),
+            }
+        }
+        if !body.is_empty() {
+            let len = self.pos - start;
+            self.add_token(TokenType::Text(body), start, len);
+        }
+    }
+    // Lex the inside of `#[...]` after the opening bracket at `start`,
+    // reusing the tag, id, class and attribute lexing of ordinary lines.
+    fn lex_interpolation(&mut self, p: &mut Peekable<Chars>, start: usize) {
+        loop {
+            let ch = match p.peek() {
+                None | Some(&'\n') => {
+                    self.error(
+                        "missing `]` to close interpolation".to_string(),
+                        start,
+                        start + 2,
+                    );
+                    return;
+                }
+                Some(&c) => c,
+            };
+            match ch {
+                s if s.is_ascii_alphabetic() => self.lex_tag(p),
+                '#' => self.lex_id(p),
+                '.' => self.lex_class(p),
+                '(' => {
+                    if !self.lex_attrs(p) {
+                        return;
+                    }
+                }
+                '/' => {
+                    let start = self.pos;
+                    self.consume_next(p);
+                    self.add_token(TokenType::Slash, start, 1);
+                }
+                ' ' => {
+                    let start = self.pos;
+                    self.consume_next(p);
+                    self.lex_text(p, start, true);
+                }
+                ']' => {
+                    let start = self.pos;
+                    self.consume_next(p);
+                    self.add_token(TokenType::InterpolationEnd, start, 1);
+                    return;
+                }
+                s => {
+                    let start = self.pos;
+                    self.consume_next(p);
+                    self.error(
+                        format!("unexpected character `{}` in interpolation", s),
+                        start,
+                        self.pos,
+                    );
+                    return;
+                }
+            }
+        }
+    }
+    // Lex `:name(opts)` filters, chained as in `:escape:markdown`, followed by
+    // their text on the same line or as an indented block.
+    fn lex_filter(&mut self, p: &mut Peekable<Chars>) {
+        while p.peek() == Some(&':') {
+            let start = self.pos;
+            self.consume_next(p);
+            let name = match self.consume_name(p) {
+                Some(name) => name,
+                None => break,
+            };
+            let len = self.pos - start;
+            self.add_token(TokenType::Filter(name), start, len);
+            if p.peek() == Some(&'(') && !self.lex_attrs(p) {
+                return;
+            }
+        }
+        match p.peek() {
+            Some(&' ') => {
+                let start = self.pos;
+                self.consume_next(p);
+                if let Some(body) = self.consume_while(p, Box::new(|c: char| -> bool { c != '\n' }))
+                {
+                    let len = self.pos - start;
+                    self.add_token(TokenType::Text(body), start, len);
+                }
+            }
+            None | Some(&'\n') => self.lex_text_block(p),
+            Some(_) => self.unexpected(p, " after filter"),
+        }
+    }
+    // Lex `include path` at the start of a line. Returns false when the line
+    // is not an include, e.g. a tag named `includes`.
+    fn lex_include(&mut self, p: &mut Peekable<Chars>) -> bool {
+        let keyword: String = p.clone().take(8).collect();
+        if !self.at_line_start() || (keyword != "include " && keyword != "include\t") {
+            return false;
+        }
+        let start = self.pos;
+        for _ in 0..8 {
+            self.consume_next(p);
+        }
+        self.consume_while(p, Box::new(|c: char| -> bool { c == ' ' || c == '\t' }));
+        let path = self
+            .consume_while(p, Box::new(|c: char| -> bool { c != '\n' }))
+            .unwrap_or_default();
+        let len = self.pos - start;
+        self.add_token(TokenType::Include(path.trim_end().to_string()), start, len);
+        true
+    }
+    // Measure the indentation of a line, checking it is made of the same
+    // character as the first indented line of the file
+    fn indent_level(&mut self, indent: &str, start: usize) -> Option<usize> {
+        let first = match indent.chars().next() {
+            Some(c) => c,
+            None => return Some(0),
+        };
+        let message = if indent.chars().any(|c| c != first) {
+            Some("mixed tabs and spaces in indentation".to_string())
+        } else {
+            match self.indent_char {
+                Some(c) if c != first => Some(format!(
+                    "indentation uses {} but the file is indented with {}",
+                    indent_name(first),
+                    indent_name(c)
+                )),
+                Some(_) => None,
+                None => {
+                    self.indent_char = Some(first);
+                    None
+                }
+            }
+        };
+        match message {
+            Some(message) => {
+                self.errors
+                    .push(Error::new(message, start, start + indent.len()));
+                None
+            }
+            None => Some(indent.len()),
+        }
+    }
+    // Whether a `:name` here starts a filter rather than a block expansion
+    fn starts_filter(&self, p: &Peekable<Chars>) -> bool {
+        let mut lookahead = p.clone();
+        lookahead.next();
+        match lookahead.peek() {
+            Some(c) if c.is_ascii_alphabetic() => (),
+            _ => return false,
+        }
+        self.at_line_start() || matches!(self.tokens.last(), Some(t) if t.ty == TokenType::Colon)
+    }
+    fn at_line_start(&self) -> bool {
+        match self.tokens.last() {
+            Some(t) => matches!(
+                t.ty,
+                TokenType::NewLine | TokenType::Indent | TokenType::Outdent
+            ),
+            None => true,
+        }
+    }
+    // Whether the last token is part of a tag, so that `<` or `>` may follow
+    fn follows_tag(&self) -> bool {
+        match self.tokens.last() {
+            Some(t) => match t.ty {
+                TokenType::Tag(_)
+                | TokenType::Id(_)
+                | TokenType::Class(_)
+                | TokenType::Attr(_, _)
+                | TokenType::Slash
+                | TokenType::TrimInner
+                | TokenType::TrimOuter => !self.src[..self.pos].ends_with(char::is_whitespace),
+                _ => false,
+            },
+            None => false,
+        }
+    }
     pub fn tokenize(&mut self) {
         let tmp = self.src.clone();
         let mut c_iter = tmp.chars().peekable();
         let mut indents = vec![0_usize];
 
         let first_line: String = tmp.chars().take_while(|&x| -> bool { x != '\n' }).collect();
-        if first_line.starts_with("doctype html") {
+        if first_line == "doctype" || first_line.starts_with("doctype ") {
             self.consume_line(&mut c_iter);
-            self.add_token(TokenType::Doctype("html".to_string()), 0, first_line.len());
+            let name = match first_line[7..].trim() {
+                "" => "html",
+                name => name,
+            };
+            self.add_token(TokenType::Doctype(name.to_string()), 0, first_line.len());
         }
 
-        'outer: loop {
+        loop {
             let ch = match c_iter.peek() {
                 None => {
                     debug!("end of file");
@@ -163,12 +680,13 @@ impl Lexer {
                 Some(&c) => c,
             };
             match ch {
+                'i' if self.lex_include(&mut c_iter) => {
+                    // Found include
+                    continue;
+                }
                 s if s.is_ascii_alphabetic() => {
                     // Found Tag
-                    let start = self.pos;
-                    let name = self.consume_name(&mut c_iter).unwrap();
-                    let len = name.len();
-                    self.add_token(TokenType::Tag(name), start, len);
+                    self.lex_tag(&mut c_iter);
                     continue;
                 }
                 '\n' => {
@@ -177,29 +695,42 @@ impl Lexer {
                     self.add_token(TokenType::NewLine, start, 1);
                     self.consume_next(&mut c_iter);
                     let start = self.pos;
-                    let level = match self
-                        .consume_while(&mut c_iter, Box::new(|c: char| -> bool { c == ' ' }))
-                    {
-                        Some(s) => s.len(),
+                    let indent = self
+                        .consume_while(&mut c_iter, Box::new(|c: char| -> bool { is_indent(c) }))
+                        .unwrap_or_default();
+                    let level = match c_iter.peek() {
+                        // Blank lines don't change the indentation
+                        Some(&'\n') => continue,
                         None => 0,
+                        Some(_) => match self.indent_level(&indent, start) {
+                            Some(level) => level,
+                            // Keep the indentation of the previous line
+                            None => indents[indents.len() - 1],
+                        },
                     };
-                    let prev = if indents.len() > 0 {
-                        indents[indents.len() - 1]
-                    } else {
-                        0
-                    };
+                    self.line_indent = level;
+                    let prev = indents[indents.len() - 1];
                     if level > prev {
                         // Found indent
-                        self.add_token(TokenType::Indent, start, level);
+                        self.add_token(TokenType::Indent, start, indent.len());
                         indents.push(level);
                     } else if level < prev {
                         // Found outdent
-                        let mut sz = prev;
-                        while level < sz && indents.len() > 0 {
-                            debug!("Outdent! actual level={}, indent level={}", &level, &sz);
+                        while level < indents[indents.len() - 1] {
+                            debug!(
+                                "Outdent! actual level={}, indent level={}",
+                                &level,
+                                &indents[indents.len() - 1]
+                            );
                             indents.pop();
-                            sz = indents[indents.len() - 1];
-                            self.add_token(TokenType::Outdent, sz, sz - level);
+                            self.add_token(TokenType::Outdent, start, indent.len());
+                        }
+                        if level > indents[indents.len() - 1] {
+                            self.errors.push(Error::new(
+                                "unindent does not match any outer indentation level".to_string(),
+                                start,
+                                start + indent.len(),
+                            ));
                         }
                     }
                     continue;
@@ -207,101 +738,31 @@ impl Lexer {
                 ' ' | '|' => {
                     // Found text (" text" or "| text" or "|text")
                     let start = self.pos;
-                    let mut len = 1;
                     let first = self.consume_next(&mut c_iter);
-                    if first == '|' && *c_iter.peek().unwrap() == ' ' {
+                    if first == '|' && c_iter.peek() == Some(&' ') {
                         self.consume_next(&mut c_iter);
-                        len += 1;
-                    }
-                    if let Some(body) =
-                        self.consume_while(&mut c_iter, Box::new(|c: char| -> bool { c != '\n' }))
-                    {
-                        len += body.len();
-                        self.add_token(TokenType::Text(body), start, len);
                     }
+                    self.lex_text(&mut c_iter, start, false);
                     continue;
                 }
                 '#' => {
                     // Found id
-                    let start = self.pos;
-                    self.consume_next(&mut c_iter);
-                    if let Some(name) = self.consume_name(&mut c_iter) {
-                        let len = self.pos - start;
-                        self.add_token(TokenType::Id(name), start, len);
-                    };
+                    self.lex_id(&mut c_iter);
                     continue;
                 }
                 '.' => {
                     // Found class
-                    let start = self.pos;
-                    self.consume_next(&mut c_iter);
-                    if let Some(name) = self.consume_name(&mut c_iter) {
-                        let len = self.pos - start;
-                        self.add_token(TokenType::Class(name), start, len);
-                    }
+                    self.lex_class(&mut c_iter);
                     continue;
                 }
                 '(' => {
                     // Found attrs
-                    self.consume_next(&mut c_iter);
-                    loop {
-                        if !c_iter.peek().is_some() {
-                            debug!("Closing parenthesis not found");
-                            break 'outer;
-                        }
-                        match *c_iter.peek().unwrap() {
-                            ')' => {
-                                self.consume_next(&mut c_iter);
-                                break;
-                            }
-                            c if c.is_ascii_whitespace() => {
-                                self.consume_whitespaces(&mut c_iter);
-                                continue;
-                            }
-                            c if c.is_ascii_alphabetic() => {
-                                // Found an attribute
-                                let start = self.pos;
-                                let name = self.consume_name(&mut c_iter).unwrap();
-                                let value: String = match c_iter.peek() {
-                                    Some(&c) if c.is_ascii_whitespace() => {
-                                        self.consume_whitespaces(&mut c_iter);
-                                        "".to_string()
-                                    }
-                                    Some(&'=') => {
-                                        self.consume_next(&mut c_iter);
-                                        match c_iter.peek() {
-                                            Some(&c) if c.is_ascii_whitespace() => {
-                                                self.consume_whitespaces(&mut c_iter);
-                                                "".to_string()
-                                            }
-                                            Some(&'"') | Some(&'\'') => {
-                                                match self.consume_quoted(&mut c_iter) {
-                                                    Some(body) => body,
-                                                    None => {
-                                                        debug!("Error: closing quote not found");
-                                                        break;
-                                                    }
-                                                }
-                                            }
-                                            Some(&_) => self
-                                                .consume_while(
-                                                    &mut c_iter,
-                                                    Box::new(|c: char| -> bool {
-                                                        !c.is_ascii_whitespace() && c != ')'
-                                                    }),
-                                                ).unwrap(),
-                                            None => "".to_string(),
-                                        }
-                                    }
-                                    _ => "".to_string(),
-                                };
-                                let len = self.pos - start;
-                                self.add_token(TokenType::Attr(name, value), start, len);
-                                continue;
-                            }
-                            _ => break,
-                        }
-                    }
+                    self.lex_attrs(&mut c_iter);
+                    continue;
+                }
+                '/' if self.at_line_start() && c_iter.clone().nth(1) == Some('/') => {
+                    // Found comment
+                    self.lex_comment(&mut c_iter);
                     continue;
                 }
                 '/' => {
@@ -311,6 +772,26 @@ impl Lexer {
                     self.add_token(TokenType::Slash, start, 1);
                     continue;
                 }
+                '<' | '>' if self.follows_tag() => {
+                    // Found whitespace control ("tag<" or "tag>")
+                    let start = self.pos;
+                    let ty = match self.consume_next(&mut c_iter) {
+                        '<' => TokenType::TrimInner,
+                        _ => TokenType::TrimOuter,
+                    };
+                    self.add_token(ty, start, 1);
+                    continue;
+                }
+                '<' => {
+                    // Found literal HTML
+                    self.lex_html(&mut c_iter);
+                    continue;
+                }
+                ':' if self.starts_filter(&c_iter) => {
+                    // Found filter
+                    self.lex_filter(&mut c_iter);
+                    continue;
+                }
                 ':' => {
                     // Found colon
                     let start = self.pos;
@@ -320,10 +801,7 @@ impl Lexer {
                     // consume ' ' after ':'
                     self.consume_while(&mut c_iter, Box::new(|c| -> bool { c == ' ' }));
                 }
-                s => {
-                    debug!("# Found an unexpected char: [{}]", s);
-                    break;
-                }
+                _ => self.unexpected(&mut c_iter, ""),
             };
         }
     }
@@ -480,6 +958,218 @@ mod tests {
             }
         ];
     }
+    #[test]
+    fn lexer_works_interpolation() {
+        let src = r#"p a #[strong.x very] b #[a(href="/x") link]"#;
+        let types: Vec<TokenType> = tokenize(src).iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::Tag("p".to_string()),
+            TokenType::Text("a ".to_string()),
+            TokenType::InterpolationStart,
+            TokenType::Tag("strong".to_string()),
+            TokenType::Class("x".to_string()),
+            TokenType::Text("very".to_string()),
+            TokenType::InterpolationEnd,
+            TokenType::Text(" b ".to_string()),
+            TokenType::InterpolationStart,
+            TokenType::Tag("a".to_string()),
+            TokenType::Attr("href".to_string(), "/x".to_string()),
+            TokenType::Text("link".to_string()),
+            TokenType::InterpolationEnd,
+        ];
+        assert!(types == expects);
+    }
+    #[test]
+    fn lexer_works_nested_interpolation() {
+        let src = "| #[em #[b x]]y \\#[z]";
+        let tokens = tokenize(src);
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::InterpolationStart,
+            TokenType::Tag("em".to_string()),
+            TokenType::InterpolationStart,
+            TokenType::Tag("b".to_string()),
+            TokenType::Text("x".to_string()),
+            TokenType::InterpolationEnd,
+            TokenType::InterpolationEnd,
+            TokenType::Text("y #[z]".to_string()),
+        ];
+        assert!(types == expects);
+        assert_eq!(tokens[0].start, 2);
+        assert_eq!(tokens[7].end, src.len());
+    }
+    #[test]
+    fn lexer_works_text_block() {
+        let src = "div\n  pre.\n    a\n      b\n\n    c\n  p";
+        let tokens = tokenize(src);
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::Tag("div".to_string()),
+            TokenType::NewLine,
+            TokenType::Indent,
+            TokenType::Tag("pre".to_string()),
+            TokenType::Text("a\n  b\n\nc".to_string()),
+            TokenType::NewLine,
+            TokenType::Tag("p".to_string()),
+        ];
+        assert!(types == expects);
+        assert_eq!(tokens[4].start, 11);
+        assert_eq!(tokens[4].end, 31);
+    }
+    #[test]
+    fn lexer_works_whitespace_control() {
+        let src = "a(href=x)> b< #c.d< e";
+        let types: Vec<TokenType> = tokenize(src).iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::Tag("a".to_string()),
+            TokenType::Attr("href".to_string(), "x".to_string()),
+            TokenType::TrimOuter,
+            TokenType::Text("b< #c.d< e".to_string()),
+        ];
+        assert!(types == expects);
+    }
+    #[test]
+    fn lexer_works_literal_html() {
+        let src = "div\n  <svg>\n    <path/>\n\n  </svg>";
+        let tokens = tokenize(src);
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::Tag("div".to_string()),
+            TokenType::NewLine,
+            TokenType::Indent,
+            TokenType::Html("<svg>\n  <path/>".to_string()),
+            TokenType::NewLine,
+            TokenType::NewLine,
+            TokenType::Html("</svg>".to_string()),
+        ];
+        assert!(types == expects);
+        assert_eq!(tokens[3].start, 6);
+        assert_eq!(tokens[3].end, 23);
+    }
+    #[test]
+    fn lexer_works_filter() {
+        let src = "div\n  :escape:markdown(a=1)\n    # Title\n  p: :cdata x";
+        let tokens = tokenize(src);
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::Tag("div".to_string()),
+            TokenType::NewLine,
+            TokenType::Indent,
+            TokenType::Filter("escape".to_string()),
+            TokenType::Filter("markdown".to_string()),
+            TokenType::Attr("a".to_string(), "1".to_string()),
+            TokenType::Text("# Title".to_string()),
+            TokenType::NewLine,
+            TokenType::Tag("p".to_string()),
+            TokenType::Colon,
+            TokenType::Filter("cdata".to_string()),
+            TokenType::Text("x".to_string()),
+        ];
+        assert!(types == expects);
+    }
+    #[test]
+    fn lexer_works_include() {
+        let src = "include _header\ndiv\n  include  foot.html \ninclude";
+        let types: Vec<TokenType> = tokenize(src).iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::Include("_header".to_string()),
+            TokenType::NewLine,
+            TokenType::Tag("div".to_string()),
+            TokenType::NewLine,
+            TokenType::Indent,
+            TokenType::Include("foot.html".to_string()),
+            TokenType::NewLine,
+            TokenType::Outdent,
+            TokenType::Tag("include".to_string()),
+        ];
+        assert!(types == expects);
+    }
+
+    #[test]
+    fn lexer_works_comment() {
+        let src = "// note\n//- hidden\n  more\n\n    nested\np";
+        let types: Vec<TokenType> = tokenize(src).iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::Comment(" note".to_string()),
+            TokenType::NewLine,
+            TokenType::UnbufferedComment(" hidden\nmore\n\n  nested".to_string()),
+            TokenType::NewLine,
+            TokenType::Tag("p".to_string()),
+        ];
+        assert!(types == expects);
+    }
+
+    #[test]
+    fn lexer_works_tabs() {
+        let src = "ul\n\tli\n\t\ta\n\n\tli";
+        let mut lex = Lexer::new(src.to_string());
+        lex.tokenize();
+        assert!(lex.get_errors().is_empty());
+        let types: Vec<TokenType> = lex.get_tokens().iter().map(|t| t.ty.clone()).collect();
+        let expects = vec![
+            TokenType::Tag("ul".to_string()),
+            TokenType::NewLine,
+            TokenType::Indent,
+            TokenType::Tag("li".to_string()),
+            TokenType::NewLine,
+            TokenType::Indent,
+            TokenType::Tag("a".to_string()),
+            TokenType::NewLine,
+            TokenType::NewLine,
+            TokenType::Outdent,
+            TokenType::Tag("li".to_string()),
+        ];
+        assert!(types == expects);
+    }
+    #[test]
+    fn lexer_reports_bad_indentation() {
+        let src = "a\n  b\n\tc\n \td\n    e\n   f";
+        let mut lex = Lexer::new(src.to_string());
+        lex.tokenize();
+        let errors: Vec<(String, (usize, usize))> = lex
+            .get_errors()
+            .iter()
+            .map(|e| (e.to_string(), e.get_span()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "indentation uses tabs but the file is indented with spaces".to_string(),
+                    (6, 7)
+                ),
+                ("mixed tabs and spaces in indentation".to_string(), (9, 11)),
+                (
+                    "unindent does not match any outer indentation level".to_string(),
+                    (19, 22)
+                ),
+            ]
+        );
+    }
+    #[test]
+    fn lexer_recovers_from_errors() {
+        let src = "p ok\n}p\na(href=\"x) y\nb #[i\np(\nbr";
+        let mut lex = Lexer::new(src.to_string());
+        lex.tokenize();
+        let errors: Vec<(String, (usize, usize))> = lex
+            .get_errors()
+            .iter()
+            .map(|e| (e.to_string(), e.get_span()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("unexpected character `}`".to_string(), (5, 6)),
+                ("missing closing quote".to_string(), (15, 20)),
+                ("missing `)` to close attributes".to_string(), (9, 10)),
+                ("missing `]` to close interpolation".to_string(), (23, 25)),
+                ("missing `)` to close attributes".to_string(), (28, 29)),
+            ]
+        );
+        // Lexing goes on after each error
+        assert!(lex.get_tokens().last().unwrap().ty == TokenType::Tag("br".to_string()));
+    }
+
     #[test]
     fn lexer_works_all_in_one() {
         let src = r##"doctype html
@@ -0,0 +1,27 @@
+extern crate encoding_rs;
+extern crate lazy_static;
+extern crate log;
+extern crate pulldown_cmark;
+extern crate regex;
+
+pub mod codegen;
+pub mod compiler;
+pub mod error;
+pub mod filter;
+pub mod fmt;
+pub mod from_html;
+pub mod input;
+pub mod js;
+pub mod json;
+pub mod lex;
+pub mod lint;
+pub mod parse;
+pub mod render;
+pub mod source_map;
+pub mod template;
+pub mod validate;
+
+pub use compiler::Compiler;
+pub use error::{Error, Result};
+pub use filter::Filter;
+pub use template::Template;
@@ -0,0 +1,593 @@
+use error::Error;
+use parse::{HTMLElement, Node};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Rule {
+    UnknownTag,
+    VoidChildren,
+    DuplicateId,
+    EmptyClass,
+    DeprecatedTag,
+    // Accessibility
+    ImgAlt,
+    AnchorHref,
+    AnchorText,
+    InputLabel,
+    HtmlLang,
+    HeadingOrder,
+    ButtonName,
+}
+
+impl Rule {
+    pub const ALL: &'static [Rule] = &[
+        Rule::UnknownTag,
+        Rule::VoidChildren,
+        Rule::DuplicateId,
+        Rule::EmptyClass,
+        Rule::DeprecatedTag,
+        Rule::ImgAlt,
+        Rule::AnchorHref,
+        Rule::AnchorText,
+        Rule::InputLabel,
+        Rule::HtmlLang,
+        Rule::HeadingOrder,
+        Rule::ButtonName,
+    ];
+
+    // The name used on the command line, e.g. `--allow unknown-tag`
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::UnknownTag => "unknown-tag",
+            Rule::VoidChildren => "void-children",
+            Rule::DuplicateId => "duplicate-id",
+            Rule::EmptyClass => "empty-class",
+            Rule::DeprecatedTag => "deprecated-tag",
+            Rule::ImgAlt => "img-alt",
+            Rule::AnchorHref => "anchor-href",
+            Rule::AnchorText => "anchor-text",
+            Rule::InputLabel => "input-label",
+            Rule::HtmlLang => "html-lang",
+            Rule::HeadingOrder => "heading-order",
+            Rule::ButtonName => "button-name",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rule> {
+        Rule::ALL.iter().cloned().find(|rule| rule.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Level {
+    Allow,
+    Warn,
+    // Reported as an error, failing the compile
+    Deny,
+}
+
+// A lint finding, with the message and span of an error
+#[derive(Clone, PartialEq, Debug)]
+pub struct Warning {
+    rule: Rule,
+    error: Error,
+}
+
+impl Warning {
+    pub fn new(rule: Rule, error: Error) -> Warning {
+        Warning { rule, error }
+    }
+    pub fn get_rule(&self) -> Rule {
+        self.rule
+    }
+    pub fn get_error(&self) -> &Error {
+        &self.error
+    }
+    // The warning as an error, for rules set to deny
+    pub fn into_error(self) -> Error {
+        let (start, end) = self.error.get_span();
+        Error::new(self.to_string(), start, end)
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}]", self.error, self.rule.name())
+    }
+}
+
+// Checks a parsed template for suspicious but valid markup. Every rule
+// warns unless its level is changed.
+pub struct Linter {
+    levels: HashMap<Rule, Level>,
+}
+
+impl Default for Linter {
+    fn default() -> Linter {
+        Linter::new()
+    }
+}
+
+impl Linter {
+    pub fn new() -> Linter {
+        Linter {
+            levels: HashMap::new(),
+        }
+    }
+
+    pub fn set_level(&mut self, rule: Rule, level: Level) {
+        self.levels.insert(rule, level);
+    }
+
+    pub fn get_level(&self, rule: Rule) -> Level {
+        *self.levels.get(&rule).unwrap_or(&Level::Warn)
+    }
+
+    pub fn check(&self, nodes: &[Node]) -> Vec<Warning> {
+        self.check_with_ids(nodes, &mut HashSet::new())
+    }
+
+    // Check with the ids of the templates checked before, for the files
+    // included into one document. The ids found are added to `ids`.
+    pub fn check_with_ids(&self, nodes: &[Node], ids: &mut HashSet<String>) -> Vec<Warning> {
+        let mut lint = Lint {
+            linter: self,
+            // Any doctype but html is an XML document with its own tags
+            xml: matches!(nodes.first(), Some(Node::Doctype(name, _)) if name != "html"),
+            ids: mem::take(ids),
+            labelled: HashSet::new(),
+            heading: None,
+            warnings: vec![],
+        };
+        collect_labels(nodes, &mut lint.labelled);
+        lint.check_nodes(nodes, Scope::default());
+        *ids = lint.ids;
+        lint.warnings
+    }
+}
+
+// The state of one run of the linter over a document
+struct Lint<'a> {
+    linter: &'a Linter,
+    xml: bool,
+    ids: HashSet<String>,
+    // Ids named by `label(for=...)`
+    labelled: HashSet<String>,
+    // Level of the last heading, `h2` is 2
+    heading: Option<usize>,
+    warnings: Vec<Warning>,
+}
+
+// Where an element is in the document
+#[derive(Clone, Copy, Default)]
+struct Scope {
+    // Inside `svg` or `math`, whose tags are not HTML
+    foreign: bool,
+    // Inside a `label`, which labels the control
+    label: bool,
+}
+
+impl<'a> Lint<'a> {
+    fn warn(&mut self, rule: Rule, message: String, (start, end): (usize, usize)) {
+        if self.linter.get_level(rule) != Level::Allow {
+            self.warnings
+                .push(Warning::new(rule, Error::new(message, start, end)));
+        }
+    }
+
+    fn check_nodes(&mut self, nodes: &[Node], scope: Scope) {
+        for node in nodes {
+            match node {
+                Node::Element(e) => self.check_element(e, scope),
+                Node::Inline(nodes) => self.check_nodes(nodes, scope),
+                _ => (),
+            }
+        }
+    }
+
+    fn check_element(&mut self, e: &HTMLElement, scope: Scope) {
+        let name = e.get_name();
+        let span = e.get_span();
+        if !self.xml && !scope.foreign {
+            self.check_accessibility(e, scope);
+            if is_deprecated(name) {
+                self.warn(
+                    Rule::DeprecatedTag,
+                    format!("`{}` is deprecated", name),
+                    span,
+                );
+            } else if !is_known(name) && !is_custom_element(name) {
+                self.warn(
+                    Rule::UnknownTag,
+                    format!("unknown HTML tag `{}`", name),
+                    span,
+                );
+            }
+        }
+        if e.is_void() && !e.is_self_closing() && !e.get_children().is_empty() {
+            self.warn(
+                Rule::VoidChildren,
+                format!("children of void element `{}` are not rendered", name),
+                span,
+            );
+        }
+        if e.get_attrs()
+            .iter()
+            .filter(|(attr, _)| attr == "id")
+            .count()
+            > 1
+        {
+            self.warn(
+                Rule::DuplicateId,
+                format!("`{}` has more than one id, browsers keep the first", name),
+                span,
+            );
+        }
+        for (attr, value) in e.get_attrs() {
+            match &attr[..] {
+                "id" if !self.ids.insert(value.to_string()) => {
+                    self.warn(Rule::DuplicateId, format!("duplicate id `{}`", value), span)
+                }
+                "class" if value.trim().is_empty() => self.warn(
+                    Rule::EmptyClass,
+                    "empty `class` attribute".to_string(),
+                    span,
+                ),
+                _ => (),
+            }
+        }
+        let scope = Scope {
+            foreign: scope.foreign || name == "svg" || name == "math",
+            label: scope.label || name == "label",
+        };
+        self.check_nodes(e.get_children(), scope);
+    }
+
+    fn check_accessibility(&mut self, e: &HTMLElement, scope: Scope) {
+        let name = e.get_name();
+        let span = e.get_span();
+        let input_type = attr(e, "type").unwrap_or("text");
+        match name {
+            "img" if attr(e, "alt").is_none() => self.warn(
+                Rule::ImgAlt,
+                "`img` without `alt`, use `alt=\"\"` for decoration".to_string(),
+                span,
+            ),
+            "a" => {
+                if attr(e, "href").is_none() {
+                    self.warn(Rule::AnchorHref, "`a` without `href`".to_string(), span);
+                }
+                if !has_name(e) {
+                    self.warn(Rule::AnchorText, "`a` without text".to_string(), span);
+                }
+            }
+            "html" if attr(e, "lang").is_none_or(|lang| lang.trim().is_empty()) => {
+                self.warn(Rule::HtmlLang, "`html` without `lang`".to_string(), span)
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                let level = name[1..].parse::<usize>().unwrap();
+                if let Some(last) = self.heading {
+                    if level > last + 1 {
+                        self.warn(
+                            Rule::HeadingOrder,
+                            format!("`{}` after `h{}` skips a heading level", name, last),
+                            span,
+                        );
+                    }
+                }
+                self.heading = Some(level);
+            }
+            "button" if !has_name(e) => self.warn(
+                Rule::ButtonName,
+                "`button` without text or `aria-label`".to_string(),
+                span,
+            ),
+            "input" if input_type == "button" && attr(e, "value").is_none() && !has_label(e) => {
+                self.warn(
+                    Rule::ButtonName,
+                    "`input(type=\"button\")` without `value`".to_string(),
+                    span,
+                )
+            }
+            "input" if input_type == "image" && attr(e, "alt").is_none() && !has_label(e) => self
+                .warn(
+                    Rule::ButtonName,
+                    "`input(type=\"image\")` without `alt`".to_string(),
+                    span,
+                ),
+            "input" | "select" | "textarea" => {
+                let labelled = scope.label
+                    || has_label(e)
+                    || attr(e, "id").is_some_and(|id| self.labelled.contains(id));
+                let control = name != "input"
+                    || !matches!(
+                        input_type,
+                        "hidden" | "submit" | "reset" | "button" | "image"
+                    );
+                if control && !labelled {
+                    self.warn(
+                        Rule::InputLabel,
+                        format!("`{}` without a `label`", name),
+                        span,
+                    );
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn attr<'a>(e: &'a HTMLElement, name: &str) -> Option<&'a str> {
+    e.get_attrs()
+        .iter()
+        .find(|(attr, _)| attr == name)
+        .map(|(_, value)| &value[..])
+}
+
+// Named by an ARIA attribute or `title`
+fn has_label(e: &HTMLElement) -> bool {
+    ["aria-label", "aria-labelledby", "title"]
+        .iter()
+        .any(|name| attr(e, name).is_some_and(|value| !value.trim().is_empty()))
+}
+
+// Has an accessible name from its label or content
+fn has_name(e: &HTMLElement) -> bool {
+    has_label(e) || has_text(e.get_children())
+}
+
+// Whether the nodes read as some text. Content not known until rendering,
+// such as filters and includes, counts as text.
+fn has_text(nodes: &[Node]) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Text(body, _) | Node::Raw(body, _) => !body.trim().is_empty(),
+        Node::Inline(nodes) => has_text(nodes),
+        Node::Element(e) if e.get_name() == "img" => {
+            attr(e, "alt").is_some_and(|alt| !alt.trim().is_empty())
+        }
+        Node::Element(e) => has_name(e),
+        Node::Filter(_) | Node::Include(_) => true,
+        _ => false,
+    })
+}
+
+fn collect_labels(nodes: &[Node], ids: &mut HashSet<String>) {
+    for node in nodes {
+        match node {
+            Node::Element(e) => {
+                if e.get_name() == "label" {
+                    if let Some(id) = attr(e, "for") {
+                        ids.insert(id.to_string());
+                    }
+                }
+                collect_labels(e.get_children(), ids);
+            }
+            Node::Inline(nodes) => collect_labels(nodes, ids),
+            _ => (),
+        }
+    }
+}
+
+pub fn is_known(name: &str) -> bool {
+    matches!(
+        name,
+        "a" | "abbr"
+            | "address"
+            | "area"
+            | "article"
+            | "aside"
+            | "audio"
+            | "b"
+            | "base"
+            | "bdi"
+            | "bdo"
+            | "blockquote"
+            | "body"
+            | "br"
+            | "button"
+            | "canvas"
+            | "caption"
+            | "cite"
+            | "code"
+            | "col"
+            | "colgroup"
+            | "data"
+            | "datalist"
+            | "dd"
+            | "del"
+            | "details"
+            | "dfn"
+            | "dialog"
+            | "div"
+            | "dl"
+            | "dt"
+            | "em"
+            | "embed"
+            | "fieldset"
+            | "figcaption"
+            | "figure"
+            | "footer"
+            | "form"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "head"
+            | "header"
+            | "hgroup"
+            | "hr"
+            | "html"
+            | "i"
+            | "iframe"
+            | "img"
+            | "input"
+            | "ins"
+            | "kbd"
+            | "label"
+            | "legend"
+            | "li"
+            | "link"
+            | "main"
+            | "map"
+            | "mark"
+            | "math"
+            | "menu"
+            | "meta"
+            | "meter"
+            | "nav"
+            | "noscript"
+            | "object"
+            | "ol"
+            | "optgroup"
+            | "option"
+            | "output"
+            | "p"
+            | "param"
+            | "picture"
+            | "pre"
+            | "progress"
+            | "q"
+            | "rp"
+            | "rt"
+            | "ruby"
+            | "s"
+            | "samp"
+            | "script"
+            | "search"
+            | "section"
+            | "select"
+            | "slot"
+            | "small"
+            | "source"
+            | "span"
+            | "strong"
+            | "style"
+            | "sub"
+            | "summary"
+            | "sup"
+            | "svg"
+            | "table"
+            | "tbody"
+            | "td"
+            | "template"
+            | "textarea"
+            | "tfoot"
+            | "th"
+            | "thead"
+            | "time"
+            | "title"
+            | "tr"
+            | "track"
+            | "u"
+            | "ul"
+            | "var"
+            | "video"
+            | "wbr"
+    )
+}
+
+pub fn is_deprecated(name: &str) -> bool {
+    matches!(
+        name,
+        "acronym"
+            | "applet"
+            | "basefont"
+            | "bgsound"
+            | "big"
+            | "blink"
+            | "center"
+            | "dir"
+            | "font"
+            | "frame"
+            | "frameset"
+            | "isindex"
+            | "keygen"
+            | "listing"
+            | "marquee"
+            | "menuitem"
+            | "multicol"
+            | "nextid"
+            | "nobr"
+            | "noembed"
+            | "noframes"
+            | "plaintext"
+            | "rb"
+            | "rtc"
+            | "spacer"
+            | "strike"
+            | "tt"
+            | "xmp"
+    )
+}
+
+// Custom elements start with a lowercase letter and contain a hyphen
+fn is_custom_element(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.contains('-')
+        && !name.chars().any(|c| c.is_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+    use parse::Parser;
+
+    fn lint(linter: &Linter, src: &str) -> Vec<String> {
+        let mut lexer = Lexer::new(src.to_string());
+        lexer.tokenize();
+        let nodes = Parser::new(lexer.get_tokens()).parse();
+        linter
+            .check(&nodes)
+            .iter()
+            .map(|w| format!("{:?} {}", w.get_error().get_span(), w))
+            .collect()
+    }
+
+    #[test]
+    fn linter_checks_accessibility() {
+        let src = "html\n  body\n    h1 Title\n    h3 Skipped\n    a(href=\"#\" alt=\"link\"): img\n    a(href=\"/\"): img(alt=\"Home\")\n    a Top\n    label(for=\"q\") Search\n    input#q\n    label Name\n      input\n    input(type=\"hidden\")\n    textarea\n    button\n    button(aria-label=\"Close\")\n    input(type=\"button\")";
+        let lints: Vec<String> = lint(&Linter::new(), src)
+            .into_iter()
+            .map(|w| w.split(' ').skip(2).collect::<Vec<&str>>().join(" "))
+            .collect();
+        assert_eq!(
+            lints,
+            vec![
+                "`html` without `lang` [html-lang]",
+                "`h3` after `h1` skips a heading level [heading-order]",
+                "`a` without text [anchor-text]",
+                "`img` without `alt`, use `alt=\"\"` for decoration [img-alt]",
+                "`a` without `href` [anchor-href]",
+                "`textarea` without a `label` [input-label]",
+                "`button` without text or `aria-label` [button-name]",
+                "`input(type=\"button\")` without `value` [button-name]",
+            ]
+        );
+    }
+
+    #[test]
+    fn linter_warns() {
+        let src = "div#a\n  Tag_Name-01\n  my-tag\n  img\n    | x\n  center\n  p#a(class=\"\")\n  svg\n    circle\n  b#c(id=\"d\")";
+        let mut linter = Linter::new();
+        assert_eq!(
+            lint(&linter, src),
+            vec![
+                "(8, 19) unknown HTML tag `Tag_Name-01` [unknown-tag]",
+                "(31, 34) `img` without `alt`, use `alt=\"\"` for decoration [img-alt]",
+                "(31, 34) children of void element `img` are not rendered [void-children]",
+                "(45, 51) `center` is deprecated [deprecated-tag]",
+                "(54, 66) duplicate id `a` [duplicate-id]",
+                "(54, 66) empty `class` attribute [empty-class]",
+                "(87, 97) `b` has more than one id, browsers keep the first [duplicate-id]",
+            ]
+        );
+        linter.set_level(Rule::UnknownTag, Level::Allow);
+        assert_eq!(lint(&linter, "doctype xml\nTag_Name-01").len(), 0);
+        assert_eq!(lint(&linter, "Tag_Name-01").len(), 0);
+    }
+}
@@ -0,0 +1,564 @@
+use pugrs::compiler::include_path;
+use pugrs::lex::{Lexer, Token, TokenType};
+use pugrs::lint;
+use pugrs::parse::{Node, Parser};
+use pugrs::{fmt, Compiler, Error};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+// LSP error codes
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// LSP symbol kinds
+const SYMBOL_FILE: u32 = 1;
+const SYMBOL_KEY: u32 = 20;
+
+// Serve the Language Server Protocol on stdin and stdout until the client
+// exits. Documents are checked with `compiler`, so the lint levels and
+// --validate given on the command line apply.
+pub fn run(compiler: &Compiler) -> Result<(), String> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut server = Server::new(compiler);
+    while let Some(message) = read_message(&mut input).map_err(|e| e.to_string())? {
+        for reply in server.handle(&message) {
+            write_message(&mut io::stdout(), &reply).map_err(|e| e.to_string())?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    match server.shutdown {
+        true => Ok(()),
+        false => Err("the client exited without shutting down".to_string()),
+    }
+}
+
+// Read a message after its `Content-Length` header, None at the end of input
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = "".to_string();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length = length.ok_or_else(|| invalid_data("missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid_data(&e.to_string()))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+struct Server<'a> {
+    compiler: &'a Compiler,
+    // The text of the open documents by URI
+    documents: HashMap<String, String>,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl<'a> Server<'a> {
+    fn new(compiler: &Compiler) -> Server<'_> {
+        Server {
+            compiler,
+            documents: HashMap::new(),
+            shutdown: false,
+            exited: false,
+        }
+    }
+
+    // Handle a request or notification, returning the messages to send back
+    fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notify(method, params),
+        };
+        // Responses to requests of the server have no method
+        if method.is_empty() {
+            return vec![];
+        }
+        let result = match method {
+            _ if self.shutdown => Err((INVALID_REQUEST, "the server is shut down".to_string())),
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                    "definitionProvider": true,
+                    "documentFormattingProvider": true,
+                },
+                "serverInfo": {"name": "pugrs", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => Ok(self.hover(params).unwrap_or(Value::Null)),
+            "textDocument/documentSymbol" => Ok(self.symbols(params)),
+            "textDocument/definition" => Ok(self.definition(params).unwrap_or(Value::Null)),
+            "textDocument/formatting" => Ok(self.formatting(params)),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+        };
+        let response = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": code, "message": message},
+            }),
+        };
+        vec![response]
+    }
+
+    fn notify(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let text = match method {
+            "textDocument/didOpen" => &params["textDocument"]["text"],
+            // The whole text, since only full sync is offered
+            "textDocument/didChange" => match params["contentChanges"].as_array() {
+                Some(changes) if !changes.is_empty() => &changes[changes.len() - 1]["text"],
+                _ => return vec![],
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish(uri, vec![])];
+            }
+            "exit" => {
+                self.exited = true;
+                return vec![];
+            }
+            _ => return vec![],
+        };
+        let text = text.as_str().unwrap_or_default().replace("\r\n", "\n");
+        self.documents.insert(uri.to_string(), text);
+        vec![self.diagnostics(uri)]
+    }
+
+    // Errors of the document, or its lint warnings when it compiles
+    fn diagnostics(&self, uri: &str) -> Value {
+        let text = &self.documents[uri];
+        let path = uri_to_path(uri);
+        let diagnostics = match self.compiler.compile_with_path(text, path.as_deref()) {
+            Ok(output) => output
+                .get_warnings()
+                .iter()
+                .map(|w| {
+                    let mut diagnostic = diagnostic(text, w.get_error(), 2);
+                    diagnostic["code"] = json!(w.get_rule().name());
+                    diagnostic
+                })
+                .collect(),
+            Err(errors) => errors.iter().map(|e| diagnostic(text, e, 1)).collect(),
+        };
+        publish(uri, diagnostics)
+    }
+
+    // The document and the byte offset of a `textDocument/*` position
+    fn locate(&self, params: &Value) -> Option<(&str, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let text = self.documents.get(uri)?;
+        Some((text, offset(text, &params["position"])))
+    }
+
+    fn hover(&self, params: &Value) -> Option<Value> {
+        let (text, offset) = self.locate(params)?;
+        let token = token_at(text, offset)?;
+        let value = match token.get_type() {
+            TokenType::Tag(name) => format!("**`<{}>`** {}", name, tag_doc(name)),
+            TokenType::Attr(name, _) => format!("**`{}`** {}", name, attr_doc(name)?),
+            _ => return None,
+        };
+        let (start, end) = token.get_span();
+        Some(json!({
+            "contents": {"kind": "markdown", "value": value},
+            "range": range(text, start, end),
+        }))
+    }
+
+    // Elements with an id and includes, in document order
+    fn symbols(&self, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let text = match self.documents.get(uri) {
+            Some(text) => text,
+            None => return json!([]),
+        };
+        let mut lexer = Lexer::new(text.to_string());
+        lexer.tokenize();
+        let nodes = Parser::new(lexer.get_tokens()).parse();
+        let mut symbols = vec![];
+        collect_symbols(text, &nodes, &mut symbols);
+        Value::Array(symbols)
+    }
+
+    // The file read by the `include` under the cursor
+    fn definition(&self, params: &Value) -> Option<Value> {
+        let (text, offset) = self.locate(params)?;
+        let path = match token_at(text, offset)?.get_type() {
+            TokenType::Include(path) => path.to_string(),
+            _ => return None,
+        };
+        let document = uri_to_path(params["textDocument"]["uri"].as_str()?)?;
+        let file = include_path(document.parent()?, &path);
+        Some(json!({"uri": path_to_uri(&file), "range": range("", 0, 0)}))
+    }
+
+    // The formatted document as a single edit. Documents with errors are
+    // left as they are.
+    fn formatting(&self, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let text = match self.documents.get(uri) {
+            Some(text) => text,
+            None => return json!([]),
+        };
+        match fmt::format(text) {
+            Ok(formatted) if formatted != *text => json!([{
+                "range": range(text, 0, text.len()),
+                "newText": formatted,
+            }]),
+            _ => json!([]),
+        }
+    }
+}
+
+fn publish(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+fn diagnostic(text: &str, e: &Error, severity: u32) -> Value {
+    let (start, end) = e.get_span();
+    json!({
+        "range": range(text, start, end),
+        "severity": severity,
+        "source": "pugrs",
+        "message": e.to_string(),
+    })
+}
+
+fn collect_symbols(text: &str, nodes: &[Node], symbols: &mut Vec<Value>) {
+    for node in nodes {
+        match node {
+            Node::Element(e) => {
+                if let Some((_, id)) = e.get_attrs().iter().find(|(name, _)| name == "id") {
+                    let name = format!("#{}", id);
+                    symbols.push(symbol(text, &name, e.get_name(), SYMBOL_KEY, e.get_span()));
+                }
+                collect_symbols(text, e.get_children(), symbols);
+            }
+            Node::Inline(nodes) => collect_symbols(text, nodes, symbols),
+            Node::Include(include) => {
+                let span = include.get_span();
+                symbols.push(symbol(
+                    text,
+                    include.get_path(),
+                    "include",
+                    SYMBOL_FILE,
+                    span,
+                ));
+            }
+            _ => (),
+        }
+    }
+}
+
+fn symbol(text: &str, name: &str, detail: &str, kind: u32, (start, end): (usize, usize)) -> Value {
+    json!({
+        "name": name,
+        "detail": detail,
+        "kind": kind,
+        "range": range(text, start, end),
+        "selectionRange": range(text, start, end),
+    })
+}
+
+// The token under the byte offset, which may be just after a token
+fn token_at(text: &str, offset: usize) -> Option<Token> {
+    let mut lexer = Lexer::new(text.to_string());
+    lexer.tokenize();
+    let tokens = lexer.get_tokens();
+    let index = tokens.iter().position(|t| {
+        let (start, end) = t.get_span();
+        start <= offset && offset <= end && !matches!(t.get_type(), TokenType::NewLine)
+    })?;
+    Some(tokens[index].clone())
+}
+
+// LSP positions count UTF-16 code units from the start of the line
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+    json!({"line": before.matches('\n').count(), "character": character})
+}
+
+fn range(text: &str, start: usize, end: usize) -> Value {
+    json!({"start": position(text, start), "end": position(text, end)})
+}
+
+// The byte offset of an LSP position, clamped to its line
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let line_start = match line {
+        0 => 0,
+        _ => match text.match_indices('\n').nth(line - 1) {
+            Some((i, _)) => i + 1,
+            None => return text.len(),
+        },
+    };
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let hex = encoded
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (encoded[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for &byte in path.to_string_lossy().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            byte => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+fn tag_doc(name: &str) -> &'static str {
+    match name {
+        "a" => "A hyperlink to `href`.",
+        "abbr" => "An abbreviation, spelled out in `title`.",
+        "article" => "A self-contained composition, such as a post or a card.",
+        "aside" => "Content indirectly related to the content around it.",
+        "audio" => "Embedded sound.",
+        "b" => "Text drawn attention to, without extra importance.",
+        "blockquote" => "A quotation from another source, cited in `cite`.",
+        "body" => "The content of the document.",
+        "br" => "A line break. Void element.",
+        "button" => "A button, submitting its form unless `type` says otherwise.",
+        "canvas" => "A bitmap drawn with scripts.",
+        "code" => "A fragment of computer code.",
+        "dd" => "The description of the preceding `dt`.",
+        "details" => "A disclosure widget, opened to show its content.",
+        "dialog" => "A dialog box or other interactive component.",
+        "div" => "A generic container for flow content.",
+        "dl" => "A description list of `dt` and `dd` pairs.",
+        "dt" => "A term described by the following `dd`.",
+        "em" => "Stressed emphasis.",
+        "fieldset" => "A group of form controls, captioned by a `legend`.",
+        "figcaption" => "The caption of a `figure`.",
+        "figure" => "Self-contained content such as an image, with a `figcaption`.",
+        "footer" => "The footer of its nearest section or of the page.",
+        "form" => "A form, submitted to `action` with `method`.",
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => "A section heading, `h1` being the highest.",
+        "head" => "Metadata of the document.",
+        "header" => "Introductory content of its nearest section or of the page.",
+        "hr" => "A thematic break between paragraphs. Void element.",
+        "html" => "The root of the document. Set the language with `lang`.",
+        "i" => "Text in an alternate voice, such as a technical term.",
+        "iframe" => "Another page embedded from `src`.",
+        "img" => "An image from `src`, described by `alt`. Void element.",
+        "input" => "A form control whose kind is set by `type`. Void element.",
+        "label" => "The caption of a form control, linked with `for` or by nesting.",
+        "legend" => "The caption of a `fieldset`.",
+        "li" => "An item of a `ul`, `ol` or `menu`.",
+        "link" => "A link to an external resource such as a stylesheet. Void element.",
+        "main" => "The dominant content of the page.",
+        "meta" => "Metadata not expressed by other elements. Void element.",
+        "nav" => "A section of navigation links.",
+        "ol" => "An ordered list of `li` items.",
+        "option" => "An option of a `select` or `datalist`.",
+        "p" => "A paragraph.",
+        "picture" => "Alternative `source`s for the `img` inside.",
+        "pre" => "Preformatted text, shown as written.",
+        "script" => "A script, inline or from `src`.",
+        "section" => "A generic section of a document, usually with a heading.",
+        "select" => "A control choosing among `option`s.",
+        "small" => "Side comments and small print.",
+        "source" => "A media resource of a `picture`, `audio` or `video`. Void element.",
+        "span" => "A generic container for phrasing content.",
+        "strong" => "Strong importance.",
+        "style" => "A stylesheet for the document.",
+        "svg" => "An embedded SVG image.",
+        "table" => "Tabular data in rows and columns.",
+        "tbody" => "The body rows of a `table`.",
+        "td" => "A data cell of a `tr`.",
+        "template" => "Markup kept inert, to be cloned by scripts.",
+        "textarea" => "A multi-line text control.",
+        "th" => "A header cell of a `tr`.",
+        "thead" => "The header rows of a `table`.",
+        "time" => "A date or time, machine-readable in `datetime`.",
+        "title" => "The title of the document, shown in the browser tab.",
+        "tr" => "A row of cells in a `table`.",
+        "ul" => "An unordered list of `li` items.",
+        "video" => "An embedded video.",
+        name if lint::is_deprecated(name) => "Deprecated HTML element.",
+        name if lint::is_known(name) => "HTML element.",
+        name if name.contains('-') => "Custom element.",
+        _ => "Unknown element.",
+    }
+}
+
+fn attr_doc(name: &str) -> Option<&'static str> {
+    let doc = match name {
+        "id" => "An identifier unique in the document.",
+        "class" => "Classes for styling and scripts, separated by spaces.",
+        "href" => "The URL the link goes to.",
+        "src" => "The URL of the embedded resource.",
+        "alt" => "Text replacing the image when it cannot be seen, empty for decoration.",
+        "title" => "Advisory information, shown as a tooltip.",
+        "lang" => "The language of the content, e.g. `en`.",
+        "type" => "The kind of control, script or resource.",
+        "name" => "The name the value of the control is submitted with.",
+        "value" => "The value of the control.",
+        "placeholder" => "A hint shown while the control is empty.",
+        "disabled" => "The control cannot be used.",
+        "checked" => "The checkbox or radio button is selected.",
+        "required" => "The control must be filled in before submitting.",
+        "for" => "The id of the control a `label` is for.",
+        "rel" => "How the linked resource relates to the document.",
+        "target" => "Where to open the link, e.g. `_blank`.",
+        "style" => "Inline CSS declarations.",
+        "width" | "height" => "The size in CSS pixels.",
+        "action" => "The URL the form is submitted to.",
+        "method" => "How the form is submitted, `get` or `post`.",
+        "role" => "The ARIA role of the element.",
+        "tabindex" => "The place of the element in keyboard navigation.",
+        "hidden" => "The element is not relevant and not shown.",
+        "aria-label" => "The accessible name of the element.",
+        "aria-labelledby" => "The ids of the elements naming this one.",
+        _ if name.starts_with("data-") => "Custom data for scripts.",
+        _ if name.starts_with("aria-") => "An ARIA state or property.",
+        _ if name.starts_with("on") => "An event handler.",
+        _ => return None,
+    };
+    Some(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn request(id: u64, method: &str, params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})
+    }
+
+    #[test]
+    fn lsp_serves_requests() {
+        let compiler = Compiler::new();
+        let mut server = Server::new(&compiler);
+        let replies = server.handle(&request(1, "initialize", json!({})));
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+
+        let uri = "file:///site/my%20page.pug";
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": uri, "text": "ul#nav\n  li: a(href=\"/\")  Home\n  include _nav\np(\n"}},
+        });
+        let replies = server.handle(&open);
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[0]["message"], "missing `)` to close attributes");
+        assert_eq!(
+            diagnostics[0]["range"],
+            json!({"start": {"line": 3, "character": 1}, "end": {"line": 3, "character": 2}})
+        );
+
+        let at = |line: u64, character: u64| json!({"textDocument": {"uri": uri}, "position": {"line": line, "character": character}});
+        let replies = server.handle(&request(2, "textDocument/hover", at(1, 6)));
+        assert_eq!(
+            replies[0]["result"]["contents"]["value"],
+            "**`<a>`** A hyperlink to `href`."
+        );
+        let replies = server.handle(&request(3, "textDocument/definition", at(2, 12)));
+        assert_eq!(replies[0]["result"]["uri"], "file:///site/_nav.pug");
+        let replies = server.handle(&request(4, "textDocument/documentSymbol", at(0, 0)));
+        let names: Vec<&Value> = replies[0]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| &symbol["name"])
+            .collect();
+        assert_eq!(names, vec!["#nav", "_nav"]);
+        // Not formatted while it has errors
+        let replies = server.handle(&request(5, "textDocument/formatting", at(0, 0)));
+        assert_eq!(replies[0]["result"], json!([]));
+
+        let replies = server.handle(&request(6, "shutdown", Value::Null));
+        assert_eq!(replies[0]["result"], Value::Null);
+        let replies = server.handle(&request(7, "textDocument/hover", at(0, 0)));
+        assert_eq!(replies[0]["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[test]
+    fn lsp_reads_messages() {
+        let body = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let framed = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut input = Cursor::new(framed.into_bytes());
+        let message = read_message(&mut input).unwrap().unwrap();
+        assert_eq!(message["method"], "exit");
+        assert!(read_message(&mut input).unwrap().is_none());
+
+        let text = "p\n  | 日本😀x";
+        let x = text.find('x').unwrap();
+        assert_eq!(position(text, x), json!({"line": 1, "character": 8}));
+        assert_eq!(offset(text, &position(text, x)), x);
+    }
+}
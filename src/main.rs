@@ -1,34 +1,82 @@
-extern crate lazy_static;
-extern crate regex;
-extern crate log;
+extern crate notify;
+extern crate pugrs;
+extern crate serde_json;
 
-use log::debug;
-mod input;
-mod lex;
-mod parse;
-mod render;
+mod batch;
+mod cli;
+mod lsp;
+mod watch;
 
-fn main() {
-    let args: Vec<String> = std::env::args().skip(1).collect();
+use cli::{Command, Options};
+use pugrs::Compiler;
+use std::path::Path;
+use std::process;
 
-    // args
-    if args.len() <= 0 {
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
         return;
     }
-    let filename: &str = &args[0];
 
-    let src = input::read_file(filename);
+    let mut compiler = Compiler::new();
+    compiler.set_encoding(options.encoding);
+    compiler.set_validate(options.validate);
+    compiler.set_source_map(options.source_map);
+    for &(rule, level) in &options.lints {
+        compiler.set_lint_level(rule, level);
+    }
+    if options.command == Command::Lsp {
+        if let Err(e) = lsp::run(&compiler) {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if options.watch {
+        if let Err(e) = watch::run(&compiler, &options) {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+        return;
+    }
 
-    let mut lexer = lex::Lexer::new(src);
-    lexer.tokenize();
-    let tokens = lexer.get_tokens();
-    debug!("Getting tokens done!");
-    // for token in tokens {
-    //     eprintln!("{:?}, {}", token, lexer.token_source(token));
-    // }
-    let mut parser = parse::Parser::new(tokens);
-    let nodes = parser.parse();
-    debug!("-------------- generate HTML! ---------------");
-    let html = render::render(nodes);
-    println!("{}", html);
+    let jobs = match batch::collect_jobs(&options) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+    };
+    let summary = batch::run(&compiler, &options, &jobs);
+    if let Some(ref depfile) = options.depfile {
+        if let Err(e) = batch::write_depfile(depfile, &jobs, &summary.deps) {
+            eprintln!("{}: error: {}", depfile, e);
+            process::exit(1);
+        }
+    }
+    if options.check {
+        eprintln!("{} files checked, {} failed", jobs.len(), summary.failed);
+    } else if options.command == Command::Fmt {
+        if options.inputs.iter().any(|input| input != "-") {
+            eprintln!(
+                "{} files reformatted, {} failed",
+                summary.written, summary.failed
+            );
+        }
+    } else if options.inputs.iter().any(|input| Path::new(input).is_dir()) {
+        eprintln!(
+            "{} files written, {} failed",
+            summary.written, summary.failed
+        );
+    }
+    if summary.failed > 0 {
+        process::exit(1);
+    }
 }
@@ -1,82 +1,199 @@
+use error::Error;
 use lex::{Token, TokenType};
 use log::debug;
 
+pub type Attrs = Vec<(String, String)>;
+
+// Spans are byte offsets in the template source
+#[derive(Clone)]
 pub enum Node {
     Empty,
+    Doctype(String, (usize, usize)),
     Element(Box<HTMLElement>),
-    Text(String),
-    Comment,
+    Text(String, (usize, usize)),
+    // A line of text mixed with `#[...]` interpolated elements
+    Inline(Vec<Node>),
+    // Literal HTML written in the template
+    Raw(String, (usize, usize)),
+    Filter(Box<FilterBlock>),
+    Include(Box<Include>),
+    // `// text`, the text after `//` and the lines nested under it
+    Comment(String, (usize, usize)),
+}
+
+impl Node {
+    pub fn get_span(&self) -> Option<(usize, usize)> {
+        match self {
+            Node::Doctype(_, span)
+            | Node::Text(_, span)
+            | Node::Raw(_, span)
+            | Node::Comment(_, span) => Some(*span),
+            Node::Element(e) => Some(e.get_span()),
+            Node::Inline(nodes) => {
+                let first = nodes.iter().find_map(Node::get_span)?;
+                let last = nodes.iter().rev().find_map(Node::get_span)?;
+                Some((first.0, last.1))
+            }
+            Node::Filter(block) => Some(block.get_span()),
+            Node::Include(include) => Some(include.get_span()),
+            Node::Empty => None,
+        }
+    }
+
+    // Move the spans of the node and its children by `by` bytes
+    pub fn shift_spans(&mut self, by: isize) {
+        let shift = |offset: &mut usize| *offset = (*offset as isize + by) as usize;
+        match self {
+            Node::Doctype(_, (start, end))
+            | Node::Text(_, (start, end))
+            | Node::Raw(_, (start, end))
+            | Node::Comment(_, (start, end)) => {
+                shift(start);
+                shift(end);
+            }
+            Node::Element(e) => {
+                shift(&mut e.start);
+                shift(&mut e.end);
+                for child in &mut e.children {
+                    child.shift_spans(by);
+                }
+            }
+            Node::Inline(nodes) => {
+                for node in nodes {
+                    node.shift_spans(by);
+                }
+            }
+            Node::Filter(block) => {
+                shift(&mut block.start);
+                shift(&mut block.end);
+            }
+            Node::Include(include) => {
+                shift(&mut include.start);
+                shift(&mut include.end);
+            }
+            Node::Empty => (),
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct HTMLElement {
     name: String,
-    attrs: Vec<(String, String)>,
+    attrs: Attrs,
     children: Vec<Node>,
+    // Explicitly closed with `tag/`
+    self_closing: bool,
+    // Whitespace control: `tag<` removes whitespace inside, `tag>` around
+    trim_inner: bool,
+    trim_outer: bool,
+    // The tag with its id, classes and attributes, children excluded
+    start: usize,
+    end: usize,
 }
 
 impl HTMLElement {
-    fn new(name: String) -> HTMLElement {
+    pub fn new(name: String, start: usize, end: usize) -> HTMLElement {
         HTMLElement {
-            name: name,
+            name,
+            start,
+            end,
             attrs: vec![],
             children: vec![],
+            self_closing: false,
+            trim_inner: false,
+            trim_outer: false,
         }
     }
-    fn push_attr(&mut self, name: String, value: String) {
+    pub fn push_attr(&mut self, name: String, value: String) {
         self.attrs.push((name, value));
     }
-    fn push_child(&mut self, child: Node) {
+    pub fn push_child(&mut self, child: Node) {
         self.children.push(child);
     }
-    pub fn render(&self, indent: usize) -> String {
-        let indent_unit = "  ";
-        let mut html = "".to_string();
-        html.push_str(&indent_unit.repeat(indent));
-        html.push('<');
-        html.push_str(&self.name);
-        for (name, value) in &self.attrs {
-            html.push(' ');
-            // TODO HTML ESCAPE
-            html.push_str(&name);
-            html.push_str(r#"=""#);
-            // TODO HTML ESCAPE
-            html.push_str(&value);
-            html.push('"');
-        }
-        html.push('>');
-        //
-        if self.children.len() > 0 {
-            html.push('\n');
-        }
-        match &self.name[0..] {
-            "area" | "base" | "br" | "col" | "embed" | "hr" | "img" | "input" | "link" | "meta"
-            | "param" | "source" | "track" | "wbr" => {
-                // No need close tag
-                html.push('\n');
-            }
-            _ => {
-                for child in &self.children {
-                    match child {
-                        Node::Element(e) => {
-                            html.push_str(&e.render(indent + 1)[0..]);
-                        }
-                        Node::Text(body) => {
-                            html.push_str(&indent_unit.repeat(indent + 1));
-                            html.push_str(&body[0..]);
-                            html.push('\n');
-                        }
-                        _ => continue,
-                    };
-                }
-
-                // Close tag
-                html.push_str(&indent_unit.repeat(indent));
-                html.push_str("</");
-                html.push_str(&self.name);
-                html.push_str(">\n");
-            }
-        }
-        html
+    pub fn set_self_closing(&mut self) {
+        self.self_closing = true;
+    }
+    pub fn is_void(&self) -> bool {
+        matches!(
+            &self.name[0..],
+            "area"
+                | "base"
+                | "br"
+                | "col"
+                | "embed"
+                | "hr"
+                | "img"
+                | "input"
+                | "link"
+                | "meta"
+                | "param"
+                | "source"
+                | "track"
+                | "wbr"
+        )
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_attrs(&self) -> &[(String, String)] {
+        &self.attrs
+    }
+    pub fn get_children(&self) -> &[Node] {
+        &self.children
+    }
+    pub fn get_children_mut(&mut self) -> &mut Vec<Node> {
+        &mut self.children
+    }
+    pub fn is_self_closing(&self) -> bool {
+        self.self_closing
+    }
+    pub fn trims_inner(&self) -> bool {
+        self.trim_inner
+    }
+    pub fn trims_outer(&self) -> bool {
+        self.trim_outer
+    }
+    pub fn get_span(&self) -> (usize, usize) {
+        (self.start, self.end)
+    }
+}
+
+// Text passed through `:name(opts)` filters
+#[derive(Clone)]
+pub struct FilterBlock {
+    // Outermost first, as written in `:escape:markdown`
+    filters: Vec<(String, Attrs)>,
+    text: String,
+    start: usize,
+    end: usize,
+}
+
+impl FilterBlock {
+    pub fn get_filters(&self) -> &[(String, Attrs)] {
+        &self.filters
+    }
+    pub fn get_text(&self) -> &str {
+        &self.text
+    }
+    pub fn get_span(&self) -> (usize, usize) {
+        (self.start, self.end)
+    }
+}
+
+// `include path`, replaced by the nodes of the file when compiling
+#[derive(Clone)]
+pub struct Include {
+    path: String,
+    start: usize,
+    end: usize,
+}
+
+impl Include {
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+    pub fn get_span(&self) -> (usize, usize) {
+        (self.start, self.end)
     }
 }
 
@@ -84,6 +201,7 @@ pub struct Parser {
     tokens: Vec<Token>,
     index: usize,
     nest: usize,
+    errors: Vec<Error>,
 }
 
 use std::boxed::Box;
@@ -91,11 +209,15 @@ use std::boxed::Box;
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Parser {
         Parser {
-            tokens: tokens,
+            tokens,
             index: 0,
             nest: 0,
+            errors: vec![],
         }
     }
+    pub fn get_errors(&self) -> &[Error] {
+        &self.errors
+    }
     fn peek(&mut self) -> Option<Token> {
         if self.tokens.len() > self.index {
             let token = self.tokens[self.index].clone();
@@ -115,87 +237,222 @@ impl Parser {
             None
         }
     }
-    fn create_element(&mut self, name: String) -> HTMLElement {
-        let mut element = HTMLElement::new(name);
-        loop {
-            if let Some(t) = self.peek() {
-                match t.get_type() {
-                    TokenType::Id(value) => {
-                        self.next();
-                        element.attrs.push(("id".to_string(), value.to_string()))
-                    }
-                    TokenType::Class(value) => {
-                        self.next();
-                        element.attrs.push(("class".to_string(), value.to_string()))
-                    }
-                    TokenType::Attr(name, value) => {
-                        self.next();
-                        element.attrs.push((name.to_string(), value.to_string()))
-                    }
-                    TokenType::Text(body) => {
-                        self.next();
-                        element.children.extend(vec![Node::Text(body.to_string())]);
-                    }
-                    TokenType::NewLine => {
-                        self.next();
-                        if let Some(t) = self.peek() {
-                            match t.get_type() {
-                                TokenType::NewLine | TokenType::Tag(_) => {
-                                    break;
-                                }
-                                _ => continue,
-                            }
-                        } else {
-                            break;
-                        }
-                    }
-                    TokenType::Indent => {
-                        self.next();
-                        self.nest += 1;
-                        debug!("start parse children {}", self.nest);
-                        element.children.extend(self.parse());
-                        debug!("end parse children {}", self.nest);
-                        self.nest -= 1;
-                    }
-                    TokenType::Colon => {
-                        self.next();
-                        self.nest += 1;
-                        debug!("start parse child {}", self.nest);
-                        element.push_child(self.parse_one());
-                        debug!("end parse child {}", self.nest);
-                        self.nest -= 1;
+    fn create_element(&mut self, name: String, start: usize, end: usize) -> HTMLElement {
+        let mut element = HTMLElement::new(name, start, end);
+        let mut slash = None;
+        while let Some(t) = self.peek() {
+            if element.children.is_empty() {
+                if let TokenType::Id(_)
+                | TokenType::Class(_)
+                | TokenType::Attr(_, _)
+                | TokenType::Slash
+                | TokenType::TrimInner
+                | TokenType::TrimOuter = t.get_type()
+                {
+                    element.end = t.get_span().1;
+                }
+            }
+            match t.get_type() {
+                TokenType::Id(value) => {
+                    self.next();
+                    element.attrs.push(("id".to_string(), value.to_string()))
+                }
+                TokenType::Class(value) => {
+                    self.next();
+                    element.attrs.push(("class".to_string(), value.to_string()))
+                }
+                TokenType::Attr(name, value) => {
+                    self.next();
+                    element.attrs.push((name.to_string(), value.to_string()))
+                }
+                TokenType::Text(_) | TokenType::InterpolationStart => {
+                    element.push_child(self.parse_text_run());
+                }
+                TokenType::NewLine => {
+                    self.next();
+                    // Blank lines may come before the children
+                    let blank = self.tokens[self.index..]
+                        .iter()
+                        .take_while(|t| *t.get_type() == TokenType::NewLine)
+                        .count();
+                    if self
+                        .tokens
+                        .get(self.index + blank)
+                        .is_some_and(|t| *t.get_type() == TokenType::Indent)
+                    {
+                        self.index += blank;
                     }
-                    TokenType::Outdent | TokenType::Slash => {
-                        break;
+                    // Only an indented block belongs to the element, any
+                    // other line is a sibling, `#id` and `| text` included
+                    match self.peek().map(|t| t.get_type().clone()) {
+                        Some(TokenType::Indent) => continue,
+                        _ => break,
                     }
-                    _ => break,
                 }
-            } else {
-                break;
+                TokenType::Indent => {
+                    self.next();
+                    self.nest += 1;
+                    debug!("start parse children {}", self.nest);
+                    element.children.extend(self.parse());
+                    debug!("end parse children {}", self.nest);
+                    self.nest -= 1;
+                    // The block ends the element
+                    break;
+                }
+                TokenType::Colon => {
+                    self.next();
+                    self.nest += 1;
+                    debug!("start parse child {}", self.nest);
+                    element.push_child(self.parse_one());
+                    debug!("end parse child {}", self.nest);
+                    self.nest -= 1;
+                }
+                TokenType::Slash => {
+                    self.next();
+                    element.self_closing = true;
+                    slash = Some(t.get_span());
+                }
+                TokenType::TrimInner => {
+                    self.next();
+                    element.trim_inner = true;
+                }
+                TokenType::TrimOuter => {
+                    self.next();
+                    element.trim_outer = true;
+                }
+                TokenType::Outdent => {
+                    break;
+                }
+                _ => break,
+            }
+        }
+        if let Some((start, end)) = slash {
+            if !element.children.is_empty() {
+                self.errors.push(Error::new(
+                    format!(
+                        "self-closing element `{}` cannot have children",
+                        element.name
+                    ),
+                    start,
+                    end,
+                ));
             }
         }
         element
     }
 
+    fn create_filter(&mut self, name: String, start: usize, end: usize) -> FilterBlock {
+        let mut block = FilterBlock {
+            filters: vec![(name, vec![])],
+            text: "".to_string(),
+            start,
+            end,
+        };
+        while let Some(t) = self.peek() {
+            match t.get_type() {
+                TokenType::Filter(name) => {
+                    block.filters.push((name.to_string(), vec![]));
+                }
+                TokenType::Attr(name, value) => {
+                    let opts = &mut block.filters.last_mut().unwrap().1;
+                    opts.push((name.to_string(), value.to_string()));
+                }
+                TokenType::Text(body) => {
+                    block.text = body.to_string();
+                }
+                _ => break,
+            }
+            self.next();
+            block.end = t.get_span().1;
+        }
+        block
+    }
+
+    // Parse a line of text, which may contain `#[...]` interpolated elements
+    fn parse_text_run(&mut self) -> Node {
+        let mut nodes: Vec<Node> = vec![];
+        while let Some(t) = self.peek() {
+            match t.get_type() {
+                TokenType::Text(body) => {
+                    self.next();
+                    nodes.push(Node::Text(body.to_string(), t.get_span()));
+                }
+                TokenType::InterpolationStart => {
+                    self.next();
+                    nodes.push(self.parse_interpolation());
+                }
+                _ => break,
+            }
+        }
+        match nodes.len() {
+            1 if matches!(nodes[0], Node::Text(_, _)) => nodes.pop().unwrap(),
+            _ => Node::Inline(nodes),
+        }
+    }
+
+    // Parse `#[...]` after its opening bracket
+    fn parse_interpolation(&mut self) -> Node {
+        let node = self.parse_one();
+        match self.peek() {
+            Some(ref t) if *t.get_type() == TokenType::InterpolationEnd => {
+                self.next();
+            }
+            _ => debug!("Parse Error: interpolation not closed"),
+        }
+        node
+    }
+
     pub fn parse_one(&mut self) -> Node {
+        if let Some(t) = self.peek() {
+            if let TokenType::Text(_) | TokenType::InterpolationStart = t.get_type() {
+                return self.parse_text_run();
+            }
+        }
         let node = match self.next() {
             Some(t) => match t.get_type() {
-                TokenType::Text(body) => Node::Text(body.to_string()),
+                TokenType::Doctype(name) => Node::Doctype(name.to_string(), t.get_span()),
+                TokenType::Html(body) => Node::Raw(body.to_string(), t.get_span()),
+                TokenType::Filter(name) => {
+                    let (start, end) = t.get_span();
+                    Node::Filter(Box::new(self.create_filter(name.to_string(), start, end)))
+                }
+                TokenType::Comment(body) => Node::Comment(body.to_string(), t.get_span()),
+                TokenType::UnbufferedComment(_) => Node::Empty,
+                TokenType::Include(path) => {
+                    let (start, end) = t.get_span();
+                    Node::Include(Box::new(Include {
+                        path: path.to_string(),
+                        start,
+                        end,
+                    }))
+                }
                 TokenType::Tag(name) => {
-                    Node::Element(Box::new(self.create_element(name.to_string())))
+                    let (start, end) = t.get_span();
+                    Node::Element(Box::new(self.create_element(name.to_string(), start, end)))
                 }
                 TokenType::Id(_id) => {
-                    let mut element = self.create_element("div".to_string());
-                    element.push_attr("id".to_string(), _id.to_string());
+                    let (start, end) = t.get_span();
+                    let mut element = self.create_element("div".to_string(), start, end);
+                    // The shorthand comes before the attributes after it
+                    element.attrs.insert(0, ("id".to_string(), _id.to_string()));
                     Node::Element(Box::new(element))
                 }
                 TokenType::Class(name) => {
-                    let mut element = self.create_element("div".to_string());
-                    element.push_attr("class".to_string(), name.to_string());
+                    let (start, end) = t.get_span();
+                    let mut element = self.create_element("div".to_string(), start, end);
+                    element
+                        .attrs
+                        .insert(0, ("class".to_string(), name.to_string()));
                     Node::Element(Box::new(element))
                 }
                 tt => {
-                    debug!("Parse Error {}", tt);
+                    let (start, end) = t.get_span();
+                    self.errors.push(Error::new(
+                        format!("unexpected {}", tt.describe()),
+                        start,
+                        end,
+                    ));
+                    self.skip_line();
                     return Node::Empty;
                 }
             },
@@ -204,28 +461,70 @@ impl Parser {
         node
     }
 
+    // Skip the tokens left on the line after an error
+    fn skip_line(&mut self) {
+        while let Some(t) = self.peek() {
+            match t.get_type() {
+                TokenType::NewLine | TokenType::Indent | TokenType::Outdent => break,
+                _ => self.next(),
+            };
+        }
+    }
+
     pub fn parse(&mut self) -> Vec<Node> {
         let mut nodes: Vec<Node> = vec![];
-        loop {
-            let node = match self.peek() {
-                Some(t) => {
-                    match t.get_type() {
-                        TokenType::Outdent => {
-                            self.next();
-                            break;
-                        }
-                        TokenType::NewLine | TokenType::Slash => {
-                            self.next();
-                            continue;
-                        }
-                        _ => (),
-                    };
-                    self.parse_one()
-                }
-                None => break,
+        while let Some(t) = self.peek() {
+            match t.get_type() {
+                TokenType::Outdent => {
+                    self.next();
+                    break;
+                }
+                TokenType::NewLine | TokenType::Slash => {
+                    self.next();
+                    continue;
+                }
+                TokenType::Indent => {
+                    // Nothing to nest the lines in, keep them as siblings
+                    let (start, end) = t.get_span();
+                    self.errors
+                        .push(Error::new("unexpected indentation".to_string(), start, end));
+                    self.next();
+                    nodes.extend(self.parse());
+                    continue;
+                }
+                _ => (),
             };
-            nodes.push(node);
+            match self.parse_one() {
+                Node::Empty => (),
+                node => nodes.push(node),
+            }
         }
         nodes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+
+    #[test]
+    fn parser_rejects_self_closing_children() {
+        let mut lexer = Lexer::new("p\n  img/\n  foo/ text\n  br/\n    span".to_string());
+        lexer.tokenize();
+        let mut parser = Parser::new(lexer.get_tokens());
+        let nodes = parser.parse();
+        assert_eq!(nodes.len(), 1);
+        let errors = parser.get_errors();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "self-closing element `foo` cannot have children",
+                "self-closing element `br` cannot have children",
+            ]
+        );
+        assert_eq!(errors[0].get_span(), (14, 15));
+        assert_eq!(errors[1].get_span(), (25, 26));
+    }
+}
@@ -1,19 +1,380 @@
-use parse::Node;
+use parse::{HTMLElement, Node};
 
-pub fn render(nodes: Vec<Node>) -> String {
-    let mut output = "".to_string();
-    for node in nodes {
-        match node {
-            Node::Element(e) => {
-                output.push_str(&e.render(0));
+pub struct Renderer {
+    indent_unit: String,
+    // Any doctype other than `html` is XML-compatible
+    xml: bool,
+    html: String,
+    // Offsets in the HTML paired with the source offsets of what is written
+    // there, when recorded for a source map
+    mappings: Option<Vec<(usize, usize)>>,
+    // Where attribute values are in the HTML, with the values before they
+    // are escaped, when recorded for code generation
+    values: Option<Vec<(usize, usize, String)>>,
+}
+
+// The HTML of a document, as written by generated code: markup and text as
+// they are, and attribute values escaped while writing
+pub enum Chunk {
+    Html(String),
+    Attr(String),
+}
+
+pub fn render(nodes: &[Node]) -> String {
+    Renderer::new().render_document(nodes).html
+}
+
+// Render and pair offsets in the HTML with the start of the span each tag,
+// text line or comment is written from, in the order of the HTML
+pub fn render_with_mappings(nodes: &[Node]) -> (String, Vec<(usize, usize)>) {
+    let mut renderer = Renderer::new();
+    renderer.mappings = Some(vec![]);
+    let renderer = renderer.render_document(nodes);
+    (renderer.html, renderer.mappings.unwrap_or_default())
+}
+
+// Render into chunks, joining the HTML between attribute values
+pub fn render_chunks(nodes: &[Node]) -> Vec<Chunk> {
+    let mut renderer = Renderer::new();
+    renderer.values = Some(vec![]);
+    let renderer = renderer.render_document(nodes);
+    let mut chunks = vec![];
+    let mut from = 0;
+    for (start, end, value) in renderer.values.unwrap_or_default() {
+        if start > from {
+            chunks.push(Chunk::Html(renderer.html[from..start].to_string()));
+        }
+        chunks.push(Chunk::Attr(value));
+        from = end;
+    }
+    chunks.push(Chunk::Html(renderer.html[from..].to_string()));
+    chunks
+}
+
+// Attribute values are written in `"`. Other characters are kept, so that
+// entities written in a value stay entities.
+pub fn escape_attr(value: &str) -> String {
+    value.replace('"', "&quot;")
+}
+
+fn doctype_declaration(name: &str) -> String {
+    match name {
+        "html" => "<!DOCTYPE html>".to_string(),
+        "xml" => r#"<?xml version="1.0" encoding="utf-8" ?>"#.to_string(),
+        "transitional" => r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN" "http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd">"#.to_string(),
+        "strict" => r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN" "http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd">"#.to_string(),
+        "frameset" => r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Frameset//EN" "http://www.w3.org/TR/xhtml1/DTD/xhtml1-frameset.dtd">"#.to_string(),
+        "1.1" => r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">"#.to_string(),
+        "basic" => r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML Basic 1.1//EN" "http://www.w3.org/TR/xhtml-basic/xhtml-basic11.dtd">"#.to_string(),
+        "mobile" => r#"<!DOCTYPE html PUBLIC "-//WAPFORUM//DTD XHTML Mobile 1.2//EN" "http://www.openmobilealliance.org/tech/DTD/xhtml-mobile12.dtd">"#.to_string(),
+        "plist" => r#"<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">"#.to_string(),
+        name => format!("<!DOCTYPE {}>", name),
+    }
+}
+
+impl Default for Renderer {
+    fn default() -> Renderer {
+        Renderer::new()
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Renderer {
+        Renderer {
+            indent_unit: "  ".to_string(),
+            xml: false,
+            html: "".to_string(),
+            mappings: None,
+            values: None,
+        }
+    }
+
+    fn render_document(mut self, nodes: &[Node]) -> Renderer {
+        if let Some(Node::Doctype(name, _)) = nodes.first() {
+            self.xml = name != "html";
+        }
+        self.render_nodes(nodes, 0);
+        self.html.push('\n');
+        self
+    }
+
+    // The HTML written so far
+    pub fn get_html(&self) -> &str {
+        &self.html
+    }
+
+    fn newline(&mut self, indent: usize) {
+        self.html.push('\n');
+        for _ in 0..indent {
+            self.html.push_str(&self.indent_unit);
+        }
+    }
+
+    // Note that what is written next comes from `start` in the source
+    fn mark(&mut self, start: usize) {
+        if let Some(ref mut mappings) = self.mappings {
+            let offset = self.html.len();
+            match mappings.last_mut() {
+                Some(last) if last.0 == offset => last.1 = start,
+                _ => mappings.push((offset, start)),
+            }
+        }
+    }
+
+    // Write the lines of `text`, indented to `indent` after the first or
+    // not at all without one, each coming from `start`
+    fn write_lines(&mut self, text: &str, indent: Option<usize>, start: usize) {
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                match indent {
+                    Some(indent) => self.newline(indent),
+                    None => self.html.push('\n'),
+                }
             }
-            Node::Text(body) => {
-                // Consider about indent
-                // TODO Escape
-                output.push_str(&body);
-            } // TODO Implement Comment
-            _ => continue,
+            self.mark(start);
+            self.html.push_str(line);
         }
     }
-    output
+
+    // Render sibling nodes one per line. The first line is not indented and
+    // the last line has no line break, so that the caller decides how the
+    // nodes are surrounded.
+    pub fn render_nodes(&mut self, nodes: &[Node], indent: usize) {
+        let mut glue = true;
+        for node in nodes {
+            if let Node::Empty | Node::Filter(_) | Node::Include(_) = node {
+                continue;
+            }
+            let trims = trims_outer(node);
+            if !glue && !trims {
+                self.newline(indent);
+            }
+            match node {
+                Node::Doctype(name, (start, _)) => {
+                    self.mark(*start);
+                    self.html.push_str(&doctype_declaration(name));
+                }
+                Node::Element(e) => self.render_element(e, indent),
+                // Text is HTML in pug, `p a <em>b</em>` keeps the tag
+                Node::Text(body, (start, _)) => self.write_lines(body, Some(indent), *start),
+                Node::Inline(nodes) => self.render_inline(nodes),
+                Node::Raw(html, (start, _)) => self.write_lines(html, Some(indent), *start),
+                Node::Comment(body, (start, _)) => self.render_comment(body, indent, *start),
+                _ => (),
+            }
+            glue = trims;
+        }
+    }
+
+    // Render nodes without adding any whitespace. Text from separate lines
+    // is still separated by a line break.
+    pub fn render_inline(&mut self, nodes: &[Node]) {
+        let mut text = false;
+        for node in nodes {
+            if let Node::Empty | Node::Filter(_) | Node::Include(_) = node {
+                continue;
+            }
+            let is_text = !matches!(node, Node::Element(_));
+            if text && is_text {
+                self.html.push('\n');
+            }
+            match node {
+                Node::Element(e) => self.render_inline_element(e),
+                Node::Text(body, (start, _)) | Node::Raw(body, (start, _)) => {
+                    self.write_lines(body, None, *start)
+                }
+                Node::Inline(nodes) => self.render_inline(nodes),
+                Node::Comment(body, (start, _)) => {
+                    self.mark(*start);
+                    self.html.push_str(&format!("<!--{}-->", body));
+                }
+                _ => (),
+            }
+            text = is_text;
+        }
+    }
+
+    // A block comment puts its lines one level deeper, between `<!--` and
+    // `-->` on lines of their own
+    fn render_comment(&mut self, body: &str, indent: usize, start: usize) {
+        self.mark(start);
+        self.html.push_str("<!--");
+        self.write_lines(body, Some(indent + 1), start);
+        if body.contains('\n') {
+            self.newline(indent);
+        }
+        self.html.push_str("-->");
+    }
+
+    // Elements without a close tag, `<br>` in HTML and `<br/>` in XML
+    fn is_empty_tag(&self, e: &HTMLElement) -> bool {
+        e.is_void() || e.is_self_closing()
+    }
+
+    fn open_tag(&mut self, e: &HTMLElement) {
+        self.html.push('<');
+        self.html.push_str(e.get_name());
+        // Classes from `.class` and `class=` make one attribute, where the
+        // first one is
+        let classes: Vec<&str> = e
+            .get_attrs()
+            .iter()
+            .filter(|(name, _)| name == "class")
+            .map(|(_, value)| &value[..])
+            .collect();
+        let mut class_written = false;
+        for (name, value) in e.get_attrs() {
+            let value = match &name[..] {
+                "class" if class_written => continue,
+                "class" => {
+                    class_written = true;
+                    classes.join(" ")
+                }
+                _ => value.to_string(),
+            };
+            self.html.push(' ');
+            // TODO HTML ESCAPE
+            self.html.push_str(name);
+            self.html.push_str(r#"=""#);
+            let start = self.html.len();
+            self.html.push_str(&escape_attr(&value));
+            if let Some(ref mut values) = self.values {
+                values.push((start, self.html.len(), value));
+            }
+            self.html.push('"');
+        }
+        if self.is_empty_tag(e) && (self.xml || !e.is_void()) {
+            self.html.push('/');
+        }
+        self.html.push('>');
+    }
+
+    // The close tag maps back to the tag too
+    fn close_tag(&mut self, e: &HTMLElement) {
+        self.mark(e.get_span().0);
+        self.html.push_str(&format!("</{}>", e.get_name()));
+    }
+
+    pub fn render_element(&mut self, e: &HTMLElement, indent: usize) {
+        self.mark(e.get_span().0);
+        self.open_tag(e);
+        if self.is_empty_tag(e) {
+            // No need close tag
+            return;
+        }
+        let children = e.get_children();
+        if e.trims_inner() || is_preformatted(e) {
+            self.render_inline(children);
+        } else if !children.is_empty() {
+            if !trims_outer(&children[0]) {
+                self.newline(indent + 1);
+            }
+            self.render_nodes(children, indent + 1);
+            if !trims_outer(&children[children.len() - 1]) {
+                self.newline(indent);
+            }
+        }
+        self.close_tag(e);
+    }
+
+    // Render on a single line, as part of a text run
+    pub fn render_inline_element(&mut self, e: &HTMLElement) {
+        self.mark(e.get_span().0);
+        self.open_tag(e);
+        if self.is_empty_tag(e) {
+            return;
+        }
+        self.render_inline(e.get_children());
+        self.close_tag(e);
+    }
+}
+
+// Elements whose content is rendered as written
+fn is_preformatted(e: &HTMLElement) -> bool {
+    matches!(e.get_name(), "pre" | "textarea" | "code")
+}
+
+fn trims_outer(node: &Node) -> bool {
+    match node {
+        Node::Element(e) => e.trims_outer(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+    use parse::Parser;
+
+    fn parse_src(src: &str) -> Vec<Node> {
+        let mut lexer = Lexer::new(src.to_string());
+        lexer.tokenize();
+        Parser::new(lexer.get_tokens()).parse()
+    }
+
+    fn render_src(src: &str) -> String {
+        render(&parse_src(src))
+    }
+
+    #[test]
+    fn renderer_closes_empty_tags() {
+        assert_eq!(
+            render_src("foo/\nbr\nbr/\nimg(src=\"a.png\")"),
+            "<foo/>\n<br>\n<br>\n<img src=\"a.png\">\n"
+        );
+        assert_eq!(
+            render_src("doctype xml\nroot\n  br\n  foo/\n  p"),
+            "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<root>\n  <br/>\n  <foo/>\n  <p></p>\n</root>\n"
+        );
+    }
+
+    #[test]
+    fn renderer_keeps_preformatted_text() {
+        assert_eq!(
+            render_src("pre\n  |  a  b\n  |   c\ntextarea\n  | x  y"),
+            "<pre> a  b\n  c</pre>\n<textarea>x  y</textarea>\n"
+        );
+    }
+
+    #[test]
+    fn renderer_trims_whitespace() {
+        assert_eq!(
+            render_src("div\n  p< a\n  p> b\n  span c\nul\n  li> x\n  li> y"),
+            "<div>\n  <p>a</p><p>\n    b\n  </p><span>\n    c\n  </span>\n</div>\n<ul><li>\n    x\n  </li><li>\n    y\n  </li></ul>\n"
+        );
+    }
+
+    #[test]
+    fn renderer_escapes_attribute_quotes() {
+        assert_eq!(
+            render_src("a(title='say \"hi\" &amp; go') p"),
+            "<a title=\"say &quot;hi&quot; &amp; go\">\n  p\n</a>\n"
+        );
+        let chunks: Vec<String> = render_chunks(&parse_src("p(id=\"x\" class='\"y\"') z"))
+            .iter()
+            .map(|chunk| match chunk {
+                Chunk::Html(html) => format!("html {}", html),
+                Chunk::Attr(value) => format!("attr {}", value),
+            })
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                "html <p id=\"",
+                "attr x",
+                "html \" class=\"",
+                "attr \"y\"",
+                "html \">\n  z\n</p>\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn renderer_ends_elements_at_siblings() {
+        assert_eq!(
+            render_src("ul\n  li one\n  div#main\n    p x\n#a\n  b\n.c(title=\"t\")\n| d"),
+            "<ul>\n  <li>\n    one\n  </li>\n  <div id=\"main\">\n    <p>\n      x\n    </p>\n  </div>\n</ul>\n<div id=\"a\">\n  <b></b>\n</div>\n<div class=\"c\" title=\"t\"></div>\nd\n"
+        );
+    }
 }
@@ -0,0 +1,190 @@
+use json;
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// A template compiled into the HTML. Its spans are moved to start at
+// `offset`, after the text of the sources before it, so that spans of
+// included files can be told apart.
+pub struct Source {
+    name: String,
+    text: String,
+    offset: usize,
+}
+
+impl Source {
+    pub fn new(name: String, text: String, offset: usize) -> Source {
+        Source { name, text, offset }
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+    // Where the spans of the next source can start
+    pub fn get_end(&self) -> usize {
+        self.offset + self.text.len() + 1
+    }
+}
+
+// A source map (version 3) from the HTML back to the templates. Lines and
+// columns count from 0, columns in UTF-16 code units as in browsers.
+pub struct SourceMap {
+    sources: Vec<Source>,
+    // Line and column in the HTML, then source, line and column
+    segments: Vec<[usize; 5]>,
+}
+
+impl SourceMap {
+    // Build from offsets in `html` paired with offsets in the spans of
+    // `sources`, in the order of the HTML
+    pub fn new(html: &str, sources: Vec<Source>, mappings: &[(usize, usize)]) -> SourceMap {
+        let line_starts: Vec<Vec<usize>> = sources
+            .iter()
+            .map(|source| {
+                let lines = source.text.match_indices('\n').map(|(i, _)| i + 1);
+                Some(0).into_iter().chain(lines).collect()
+            })
+            .collect();
+        let mut segments = Vec::with_capacity(mappings.len());
+        let (mut line, mut line_start, mut from) = (0, 0, 0);
+        for &(offset, span) in mappings {
+            for (i, _) in html[from..offset].match_indices('\n') {
+                line += 1;
+                line_start = from + i + 1;
+            }
+            from = offset;
+            let index = match sources.iter().rposition(|s| s.offset <= span) {
+                Some(index) => index,
+                None => continue,
+            };
+            let text = &sources[index].text;
+            let span = (span - sources[index].offset).min(text.len());
+            let starts = &line_starts[index];
+            let source_line = starts.partition_point(|&start| start <= span) - 1;
+            segments.push([
+                line,
+                utf16_len(&html[line_start..offset]),
+                index,
+                source_line,
+                utf16_len(&text[starts[source_line]..span]),
+            ]);
+        }
+        SourceMap { sources, segments }
+    }
+
+    pub fn get_sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    // The source name, line and column an HTML position was written from
+    pub fn lookup(&self, line: usize, column: usize) -> Option<(&str, usize, usize)> {
+        let segment = self
+            .segments
+            .iter()
+            .rev()
+            .find(|s| s[0] == line && s[1] <= column)?;
+        Some((&self.sources[segment[2]].name, segment[3], segment[4]))
+    }
+
+    // The map as JSON, for the HTML written to `file`. The templates are
+    // included, so that the map can be read without them.
+    pub fn to_json(&self, file: &str) -> String {
+        let names: Vec<String> = self.sources.iter().map(|s| json::string(&s.name)).collect();
+        let texts: Vec<String> = self.sources.iter().map(|s| json::string(&s.text)).collect();
+        format!(
+            r#"{{"version":3,"file":{},"sources":[{}],"sourcesContent":[{}],"names":[],"mappings":{}}}"#,
+            json::string(file),
+            names.join(","),
+            texts.join(","),
+            json::string(&self.mappings())
+        )
+    }
+
+    // Segments of a line are separated by `,` and lines by `;`. Each field
+    // is the difference to the previous segment, except that the column in
+    // the HTML starts from 0 on every line.
+    fn mappings(&self) -> String {
+        let mut mappings = "".to_string();
+        let mut previous = [0; 5];
+        for segment in &self.segments {
+            if segment[0] > previous[0] {
+                for _ in previous[0]..segment[0] {
+                    mappings.push(';');
+                }
+                previous[1] = 0;
+            } else if !mappings.is_empty() {
+                mappings.push(',');
+            }
+            for field in 1..5 {
+                vlq(
+                    &mut mappings,
+                    segment[field] as i64 - previous[field] as i64,
+                );
+            }
+            previous = *segment;
+        }
+        mappings
+    }
+}
+
+fn utf16_len(s: &str) -> usize {
+    s.chars().map(char::len_utf16).sum()
+}
+
+// Base64 VLQ: 5 bits per digit from the lowest, the sign in the lowest bit
+fn vlq(out: &mut String, value: i64) {
+    let mut value = match value < 0 {
+        true => (-value as u64) << 1 | 1,
+        false => (value as u64) << 1,
+    };
+    loop {
+        let mut digit = value & 31;
+        value >>= 5;
+        if value > 0 {
+            digit |= 32;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+    use parse::Parser;
+    use render;
+
+    #[test]
+    fn source_map_encodes() {
+        let mut out = "".to_string();
+        for value in &[0, 1, -1, 16, -123] {
+            vlq(&mut out, *value);
+        }
+        assert_eq!(out, "ACDgB3H");
+
+        let src = "ul\n  li: a(href=\"/\") Héllo #[em 😀]\n  li two";
+        let mut lexer = Lexer::new(src.to_string());
+        lexer.tokenize();
+        let nodes = Parser::new(lexer.get_tokens()).parse();
+        let (html, mappings) = render::render_with_mappings(&nodes);
+        assert_eq!(
+            html,
+            "<ul>\n  <li>\n    <a href=\"/\">\n      Héllo <em>😀</em>\n    </a>\n  </li>\n  <li>\n    two\n  </li>\n</ul>\n"
+        );
+        let source = Source::new("page.pug".to_string(), src.to_string(), 0);
+        let map = SourceMap::new(&html, vec![source], &mappings);
+        assert_eq!(map.lookup(2, 5), Some(("page.pug", 1, 6)));
+        assert_eq!(map.lookup(3, 17), Some(("page.pug", 1, 28)));
+        assert_eq!(map.lookup(3, 18), Some(("page.pug", 1, 26)));
+        assert_eq!(map.lookup(7, 4), Some(("page.pug", 2, 4)));
+        assert_eq!(map.lookup(10, 0), None);
+        assert_eq!(
+            map.to_json("page.html"),
+            r#"{"version":3,"file":"page.html","sources":["page.pug"],"sourcesContent":["ul\n  li: a(href=\"/\") Héllo #[em 😀]\n  li two"],"names":[],"mappings":"AAAA;EACE;IAAI;MAAW,MAAS,IAAE,EAAF;IAApB;EAAJ;EACA;IAAE;EAAF;AAFF"}"#
+        );
+    }
+}
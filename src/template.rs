@@ -0,0 +1,13 @@
+use std::fmt;
+
+// A template compiled into a type by `#[derive(Template)]` from pugrs-macros
+pub trait Template {
+    fn render_into(&self, w: &mut dyn fmt::Write) -> fmt::Result;
+
+    fn render(&self) -> String {
+        let mut html = "".to_string();
+        // Writing to a String does not fail
+        let _ = self.render_into(&mut html);
+        html
+    }
+}
@@ -0,0 +1,181 @@
+use error::Error;
+use parse::{HTMLElement, Node};
+
+// Check the nesting of elements against the HTML content models, for
+// markup that browsers silently repair into a different tree. Only parents
+// in the same template are known, so the top-level elements of a template,
+// e.g. the `li`s of an included partial, are not checked against theirs.
+pub fn validate(nodes: &[Node]) -> Vec<Error> {
+    let mut errors = vec![];
+    validate_nodes(nodes, &mut vec![], &mut errors);
+    errors
+}
+
+fn validate_nodes<'a>(nodes: &'a [Node], ancestors: &mut Vec<&'a str>, errors: &mut Vec<Error>) {
+    for node in nodes {
+        match node {
+            Node::Element(e) => validate_element(e, ancestors, errors),
+            Node::Inline(nodes) => validate_nodes(nodes, ancestors, errors),
+            _ => (),
+        }
+    }
+}
+
+fn validate_element<'a>(e: &'a HTMLElement, ancestors: &mut Vec<&'a str>, errors: &mut Vec<Error>) {
+    let name = e.get_name();
+    // The content of svg and math is not HTML
+    if name == "svg" || name == "math" {
+        return;
+    }
+    if let Some(message) = misplaced(e, ancestors) {
+        let (start, end) = e.get_span();
+        errors.push(Error::new(message, start, end));
+    }
+    ancestors.push(name);
+    validate_nodes(e.get_children(), ancestors, errors);
+    ancestors.pop();
+}
+
+// Why `e` cannot be where it is, if it cannot
+fn misplaced(e: &HTMLElement, ancestors: &[&str]) -> Option<String> {
+    let name = e.get_name();
+    let parent = *ancestors.last()?;
+    if parent == "template" {
+        return None;
+    }
+    match name {
+        "li" if !matches!(parent, "ul" | "ol" | "menu") => {
+            return Some("`li` must be inside `ul`, `ol` or `menu`".to_string());
+        }
+        "tr" if parent == "table" => {
+            return Some(
+                "`tr` must be inside `thead`, `tbody` or `tfoot`, browsers add a `tbody` around it"
+                    .to_string(),
+            );
+        }
+        "tr" if !matches!(parent, "thead" | "tbody" | "tfoot") => {
+            return Some("`tr` must be inside `thead`, `tbody` or `tfoot`".to_string());
+        }
+        "td" | "th" if parent != "tr" => {
+            return Some(format!("`{}` must be inside `tr`", name));
+        }
+        "thead" | "tbody" | "tfoot" | "caption" | "colgroup" if parent != "table" => {
+            return Some(format!("`{}` must be inside `table`", name));
+        }
+        "a" if ancestors.contains(&"a") => {
+            return Some("`a` cannot be inside another `a`".to_string());
+        }
+        "title" | "base" if ancestors.contains(&"body") => {
+            return Some(format!("`{}` belongs in `head`, not `body`", name));
+        }
+        // `meta(itemprop)` is microdata, which may be anywhere
+        "meta"
+            if ancestors.contains(&"body")
+                && e.get_attrs().iter().all(|(attr, _)| attr != "itemprop") =>
+        {
+            return Some("`meta` belongs in `head`, not `body`".to_string());
+        }
+        _ => (),
+    }
+    // A `p` is still open inside its inline children, up to the elements
+    // that start a new scope for it, such as `button` and `table`
+    let open_p = ancestors
+        .iter()
+        .rev()
+        .take_while(|a| !is_p_scope(a))
+        .any(|a| *a == "p");
+    if open_p && is_block(name) {
+        return Some(format!(
+            "`{}` cannot be inside `p`, browsers close the `p` before it",
+            name
+        ));
+    }
+    None
+}
+
+// Elements that hide the `p` around them from the ones inside, in the
+// button scope of the HTML parser
+fn is_p_scope(name: &str) -> bool {
+    matches!(
+        name,
+        "applet"
+            | "button"
+            | "caption"
+            | "html"
+            | "marquee"
+            | "object"
+            | "table"
+            | "td"
+            | "template"
+            | "th"
+    )
+}
+
+// Flow content that is not phrasing content, which ends an open `p`
+pub fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "address"
+            | "article"
+            | "aside"
+            | "blockquote"
+            | "details"
+            | "dialog"
+            | "div"
+            | "dl"
+            | "fieldset"
+            | "figcaption"
+            | "figure"
+            | "footer"
+            | "form"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "header"
+            | "hgroup"
+            | "hr"
+            | "main"
+            | "menu"
+            | "nav"
+            | "ol"
+            | "p"
+            | "pre"
+            | "search"
+            | "section"
+            | "table"
+            | "ul"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+    use parse::Parser;
+
+    #[test]
+    fn validate_content_models() {
+        let src = "body\n  ul\n    li ok\n  div\n    li\n  p\n    span ok\n    div\n  a\n    b #[a x]\n  table\n    tr\n  title\n  meta(itemprop=\"x\")\n  svg\n    a\n      title\n  p\n    span: div\n    button: div\nli";
+        let mut lexer = Lexer::new(src.to_string());
+        lexer.tokenize();
+        let nodes = Parser::new(lexer.get_tokens()).parse();
+        let errors: Vec<String> = validate(&nodes)
+            .iter()
+            .map(|e| format!("{:?} {}", e.get_span(), e))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "(30, 32) `li` must be inside `ul`, `ol` or `menu`",
+                "(53, 56) `div` cannot be inside `p`, browsers close the `p` before it",
+                "(69, 70) `a` cannot be inside another `a`",
+                "(86, 88) `tr` must be inside `thead`, `tbody` or `tfoot`, browsers add a `tbody` around it",
+                "(91, 96) `title` belongs in `head`, not `body`",
+                "(156, 159) `div` cannot be inside `p`, browsers close the `p` before it",
+            ]
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use batch;
+use batch::Job;
+use cli::Options;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use pugrs::Compiler;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+// Changes arriving within this time are compiled together, since editors
+// often write a file in several steps
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+// Which jobs have to be compiled again when a file changes
+struct Graph {
+    dependents: HashMap<PathBuf, Vec<usize>>,
+}
+
+impl Graph {
+    // `deps` has the files read by the last compile of each input
+    fn new(jobs: &[Job], deps: &HashMap<String, Vec<PathBuf>>) -> Graph {
+        let mut dependents: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+        for (i, job) in jobs.iter().enumerate() {
+            // A template not compiled yet depends on itself only
+            let own = vec![PathBuf::from(&job.input)];
+            for file in deps.get(&job.input).unwrap_or(&own) {
+                dependents.entry(canonical(file)).or_default().push(i);
+            }
+        }
+        Graph { dependents }
+    }
+
+    fn dependents(&self, file: &Path) -> &[usize] {
+        match self.dependents.get(file) {
+            Some(jobs) => jobs,
+            None => &[],
+        }
+    }
+}
+
+// The directories of the files read by the last compiles. They are watched
+// on top of the inputs, for includes outside them, and rather than the
+// files themselves since editors often replace a file when saving it.
+fn dep_dirs(deps: &HashMap<String, Vec<PathBuf>>) -> HashSet<PathBuf> {
+    deps.values()
+        .flatten()
+        .filter_map(|file| canonical(file).parent().map(Path::to_path_buf))
+        .collect()
+}
+
+// Watch the directories of the files read now, and stop watching those no
+// file is read from any more
+fn update_watches<W: Watcher>(
+    watcher: &mut W,
+    watched: &mut HashSet<PathBuf>,
+    deps: &HashMap<String, Vec<PathBuf>>,
+) {
+    let dirs = dep_dirs(deps);
+    for dir in watched.difference(&dirs) {
+        let _ = watcher.unwatch(dir);
+    }
+    watched.retain(|dir| dirs.contains(dir));
+    for dir in dirs {
+        if watched.contains(&dir) {
+            continue;
+        }
+        match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watched.insert(dir);
+            }
+            Err(e) => eprintln!("watch error: {}: {}", dir.display(), e),
+        }
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Compile everything once, then again whenever a template or a file it
+// includes changes, wherever that file is. Errors are reported without stopping the watcher.
+pub fn run(compiler: &Compiler, options: &Options) -> Result<(), String> {
+    if options.inputs.iter().any(|input| input == "-") {
+        return Err("--watch cannot read stdin".to_string());
+    }
+    let mut jobs = batch::collect_jobs(options)?;
+    let summary = batch::run(compiler, options, &jobs);
+    let mut deps: HashMap<String, Vec<PathBuf>> = jobs
+        .iter()
+        .map(|job| job.input.clone())
+        .zip(summary.deps)
+        .collect();
+    eprintln!(
+        "{} files written, {} failed",
+        summary.written, summary.failed
+    );
+
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(tx).map_err(|e| e.to_string())?;
+    for input in &options.inputs {
+        watcher
+            .watch(Path::new(input), RecursiveMode::Recursive)
+            .map_err(|e| format!("{}: {}", input, e))?;
+    }
+    let mut watched = HashSet::new();
+    update_watches(&mut watcher, &mut watched, &deps);
+    eprintln!("watching for changes...");
+
+    while let Ok(event) = rx.recv() {
+        let mut changed = HashSet::new();
+        collect_changes(event, &mut changed);
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            collect_changes(event, &mut changed);
+        }
+        // Templates may have been added or removed in watched directories.
+        // When they cannot be listed, the last ones listed are compiled.
+        match batch::collect_jobs(options) {
+            Ok(collected) => jobs = collected,
+            Err(e) => eprintln!("error: {}", e),
+        }
+        let graph = Graph::new(&jobs, &deps);
+        let mut affected: Vec<usize> = changed
+            .iter()
+            .flat_map(|path| graph.dependents(path).to_vec())
+            .collect();
+        affected.sort();
+        affected.dedup();
+        for i in affected {
+            let job = &jobs[i];
+            let report = batch::compile_job(compiler, options, job);
+            if report.print() {
+                eprintln!("compiled {}", job.input);
+                deps.insert(job.input.clone(), report.deps);
+                continue;
+            }
+            // A failed compile may not have read all the includes, so the
+            // files read before are still watched, with those read now
+            let files = deps.entry(job.input.clone()).or_default();
+            for file in report.deps {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        update_watches(&mut watcher, &mut watched, &deps);
+    }
+    Ok(())
+}
+
+fn collect_changes(event: notify::Result<Event>, changed: &mut HashSet<PathBuf>) {
+    match event {
+        Ok(event) => match event.kind {
+            EventKind::Create(_) | EventKind::Modify(_) => {
+                changed.extend(event.paths.iter().map(|path| canonical(path)))
+            }
+            _ => (),
+        },
+        Err(e) => eprintln!("watch error: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(input: &str) -> Job {
+        Job {
+            input: input.to_string(),
+            output: None,
+        }
+    }
+
+    #[test]
+    fn graph_finds_dependents() {
+        let jobs = vec![job("/t/a.pug"), job("/t/b.pug"), job("/t/new.pug")];
+        let mut deps = HashMap::new();
+        deps.insert(
+            "/t/a.pug".to_string(),
+            vec![PathBuf::from("/t/a.pug"), PathBuf::from("/inc/nav.pug")],
+        );
+        deps.insert(
+            "/t/b.pug".to_string(),
+            vec![PathBuf::from("/t/b.pug"), PathBuf::from("/inc/nav.pug")],
+        );
+        let graph = Graph::new(&jobs, &deps);
+        assert_eq!(graph.dependents(Path::new("/inc/nav.pug")), [0, 1]);
+        assert_eq!(graph.dependents(Path::new("/t/b.pug")), [1]);
+        assert_eq!(graph.dependents(Path::new("/t/new.pug")), [2]);
+        assert!(graph.dependents(Path::new("/inc/other.pug")).is_empty());
+        assert_eq!(
+            dep_dirs(&deps),
+            HashSet::from([PathBuf::from("/t"), PathBuf::from("/inc")])
+        );
+
+        // After `b` stops including the nav, a change to it only compiles `a`
+        deps.insert(
+            "/t/b.pug".to_string(),
+            vec![PathBuf::from("/t/b.pug"), PathBuf::from("/inc/footer.pug")],
+        );
+        let graph = Graph::new(&jobs, &deps);
+        assert_eq!(graph.dependents(Path::new("/inc/nav.pug")), [0]);
+        assert_eq!(graph.dependents(Path::new("/inc/footer.pug")), [1]);
+    }
+}